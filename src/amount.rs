@@ -0,0 +1,290 @@
+use {
+    serde::{de, Deserialize, Deserializer, Serialize, Serializer},
+    std::{convert::TryFrom, fmt, str::FromStr},
+    strum_macros::EnumString,
+};
+
+pub const DECIMALS: usize = 4;
+const SCALE: i64 = 10_000;
+
+/// Exact money amount, stored as a count of ten-thousandths.
+/// Balances never carry more decimals than the input, so they don't need rounding
+/// between operations: only the output is rounded.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Amount(i64);
+
+/// How to round an amount to fewer decimals.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, EnumString)]
+#[strum(serialize_all = "kebab-case")]
+pub enum RoundingMode {
+    /// Half away from zero
+    #[default]
+    HalfUp,
+    /// Half to even, aka banker's rounding
+    HalfEven,
+    Truncate,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum ParseAmountError {
+    Empty,
+    InvalidDigit,
+    NonFinite,
+    TooManyDecimals,
+    Overflow,
+}
+
+impl fmt::Display for ParseAmountError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            fmt,
+            "{}",
+            match self {
+                Self::Empty => String::from("Empty amount"),
+                Self::InvalidDigit => String::from("Invalid digit in amount"),
+                Self::NonFinite => String::from("Amount is not a finite number"),
+                Self::TooManyDecimals =>
+                    format!("Amount has more than {} decimal places", DECIMALS),
+                Self::Overflow => String::from("Amount is too large"),
+            }
+        )
+    }
+}
+
+impl std::error::Error for ParseAmountError {}
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+    /// Largest representable amount, a bit over 922 trillion.
+    pub const MAX: Amount = Amount(i64::MAX);
+
+    pub fn from_units(units: i64) -> Self {
+        Self(units)
+    }
+    pub fn units(self) -> i64 {
+        self.0
+    }
+    pub fn checked_add(self, other: Amount) -> Option<Amount> {
+        self.0.checked_add(other.0).map(Amount)
+    }
+    pub fn checked_sub(self, other: Amount) -> Option<Amount> {
+        self.0.checked_sub(other.0).map(Amount)
+    }
+    pub fn checked_neg(self) -> Option<Amount> {
+        self.0.checked_neg().map(Amount)
+    }
+    /// Product of the amounts, rounded half up to the nearest unit
+    pub fn checked_mul(self, factor: Amount) -> Option<Amount> {
+        self.scaled(factor, 1)
+    }
+    /// `rate` percent of the amount, rounded half up to the nearest unit
+    pub fn percentage(self, rate: Amount) -> Option<Amount> {
+        self.scaled(rate, 100)
+    }
+    fn scaled(self, factor: Amount, divisor: i128) -> Option<Amount> {
+        let product = i128::from(self.0) * i128::from(factor.0);
+        let divisor = divisor * i128::from(SCALE);
+        let units = (product.abs() + divisor / 2) / divisor * product.signum();
+        i64::try_from(units).ok().map(Amount)
+    }
+    pub fn round(self, decimals: usize, mode: RoundingMode) -> Amount {
+        if decimals >= DECIMALS {
+            return self;
+        }
+        let factor = 10u64.pow((DECIMALS - decimals) as u32);
+        let units = self.0.unsigned_abs();
+        let (quotient, remainder) = (units / factor, units % factor);
+        let round_up = match mode {
+            RoundingMode::HalfUp => 2 * remainder >= factor,
+            RoundingMode::HalfEven => {
+                2 * remainder > factor || (2 * remainder == factor && quotient % 2 == 1)
+            }
+            RoundingMode::Truncate => false,
+        };
+        let units = i64::try_from((quotient + round_up as u64) * factor).unwrap_or(i64::MAX);
+        Amount(if self.0 < 0 { -units } else { units })
+    }
+    /// Printed form of an amount rounded to `decimals` places: as `Display`, but
+    /// without fractional part when there are no decimals.
+    pub fn format(self, decimals: usize) -> String {
+        if decimals == 0 {
+            (self.0 / SCALE).to_string()
+        } else {
+            self.to_string()
+        }
+    }
+}
+
+impl FromStr for Amount {
+    type Err = ParseAmountError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (negative, digits) = match s.as_bytes().first() {
+            Some(b'-') => (true, &s[1..]),
+            Some(b'+') => (false, &s[1..]),
+            _ => (false, s),
+        };
+        if ["inf", "infinity", "nan"]
+            .iter()
+            .any(|non_finite| digits.eq_ignore_ascii_case(non_finite))
+        {
+            return Err(ParseAmountError::NonFinite);
+        }
+        let (integer, fraction) = match digits.split_once('.') {
+            Some((integer, fraction)) => (integer, fraction),
+            None => (digits, ""),
+        };
+        if integer.is_empty() && fraction.is_empty() {
+            return Err(ParseAmountError::Empty);
+        }
+        if !integer
+            .bytes()
+            .chain(fraction.bytes())
+            .all(|b| b.is_ascii_digit())
+        {
+            return Err(ParseAmountError::InvalidDigit);
+        }
+        if fraction.len() > DECIMALS {
+            return Err(ParseAmountError::TooManyDecimals);
+        }
+        let mut units: i64 = 0;
+        for b in integer.bytes().chain(
+            fraction
+                .bytes()
+                .chain(std::iter::repeat(b'0'))
+                .take(DECIMALS),
+        ) {
+            units = units
+                .checked_mul(10)
+                .and_then(|units| units.checked_add(i64::from(b - b'0')))
+                .ok_or(ParseAmountError::Overflow)?;
+        }
+        Ok(Self(if negative { -units } else { units }))
+    }
+}
+
+/// Panics if the string is not a valid amount, use `str::parse` for fallible parsing.
+impl From<&str> for Amount {
+    fn from(s: &str) -> Self {
+        s.parse()
+            .unwrap_or_else(|e| panic!("Invalid amount {:?}: {}", s, e))
+    }
+}
+
+impl fmt::Display for Amount {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let units = self.0.unsigned_abs();
+        let scale = SCALE as u64;
+        let fraction = format!("{:0width$}", units % scale, width = DECIMALS);
+        let fraction = fraction.trim_end_matches('0');
+        write!(
+            fmt,
+            "{}{}.{}",
+            sign,
+            units / scale,
+            if fraction.is_empty() { "0" } else { fraction }
+        )
+    }
+}
+
+impl Serialize for Amount {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Amount {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct AmountVisitor;
+
+        impl<'de> de::Visitor<'de> for AmountVisitor {
+            type Value = Amount;
+
+            fn expecting(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+                write!(fmt, "a decimal amount with at most {} decimals", DECIMALS)
+            }
+
+            fn visit_str<E: de::Error>(self, s: &str) -> Result<Amount, E> {
+                s.parse().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_str(AmountVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_display() {
+        assert_eq!("2.0", Amount::from("2").to_string());
+        assert_eq!("1.4545", Amount::from("1.4545").to_string());
+        assert_eq!("0.5", Amount::from(".5").to_string());
+        assert_eq!("-3.01", Amount::from("-3.01").to_string());
+        assert_eq!(Amount::from_units(20_000), Amount::from("2.0000"));
+        assert_eq!("-3", Amount::from("-3.0").format(0));
+        assert_eq!("2.5", Amount::from("2.5").format(2));
+    }
+
+    #[test]
+    fn reject_invalid() {
+        assert_eq!(
+            Err(ParseAmountError::TooManyDecimals),
+            "1.45454".parse::<Amount>()
+        );
+        assert_eq!(Err(ParseAmountError::InvalidDigit), "1e5".parse::<Amount>());
+        assert_eq!(Err(ParseAmountError::Empty), ".".parse::<Amount>());
+        assert_eq!(Err(ParseAmountError::NonFinite), "-inf".parse::<Amount>());
+        assert_eq!(Err(ParseAmountError::NonFinite), "NaN".parse::<Amount>());
+        assert_eq!(
+            Err(ParseAmountError::Overflow),
+            "99999999999999999999".parse::<Amount>()
+        );
+    }
+
+    #[test]
+    fn rounding() {
+        let amount = Amount::from("2.2345");
+        assert_eq!(
+            Amount::from("2.234"),
+            amount.round(3, RoundingMode::HalfEven)
+        );
+        assert_eq!(Amount::from("2.235"), amount.round(3, RoundingMode::HalfUp));
+        assert_eq!(
+            Amount::from("2.234"),
+            amount.round(3, RoundingMode::Truncate)
+        );
+        let amount = Amount::from("-2.2355");
+        assert_eq!(
+            Amount::from("-2.236"),
+            amount.round(3, RoundingMode::HalfEven)
+        );
+        assert_eq!(
+            Amount::from("-2.236"),
+            amount.round(3, RoundingMode::HalfUp)
+        );
+        assert_eq!(
+            Amount::from("-2.235"),
+            amount.round(3, RoundingMode::Truncate)
+        );
+        assert_eq!(amount, amount.round(4, RoundingMode::Truncate));
+    }
+
+    #[test]
+    fn exact_arithmetic() {
+        let mut total = Amount::ZERO;
+        for _ in 0..10 {
+            total = total.checked_add(Amount::from("0.1")).unwrap();
+        }
+        assert_eq!(Amount::from("1"), total);
+    }
+}
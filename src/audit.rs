@@ -0,0 +1,76 @@
+use {
+    crate::{Amount, ClientAccounts, ClientId, TransactionId, TransactionKind},
+    serde::{Deserialize, Serialize},
+    std::{
+        collections::BTreeMap,
+        io::{Read, Write},
+    },
+};
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AuditEntry {
+    pub tx: TransactionId,
+    pub client: ClientId,
+    pub kind: TransactionKind,
+    pub available_before: Amount,
+    pub held_before: Amount,
+    pub available_after: Amount,
+    pub held_after: Amount,
+    /// "ok" or the error message
+    pub result: String,
+}
+
+/// Record of every processed transaction order, with its effect on the client balances.
+#[derive(Debug, Default)]
+pub struct AuditLog {
+    entries: Vec<AuditEntry>,
+}
+
+impl AuditLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn push(&mut self, entry: AuditEntry) {
+        self.entries.push(entry);
+    }
+    pub fn entries(&self) -> &[AuditEntry] {
+        &self.entries
+    }
+    pub fn write_csv_to<W: Write>(&self, w: &mut W) -> Result<(), csv::Error> {
+        let mut writer = csv::Writer::from_writer(w);
+        for entry in &self.entries {
+            writer.serialize(entry)?
+        }
+        Ok(())
+    }
+}
+
+/// Rebuilds the balances from an audit log written by `AuditLog::write_csv_to`,
+/// as an independent check of a run. Rejected orders are ignored.
+/// Accounts are rebuilt in the implicit currency, without their volume.
+pub fn replay_audit<R: Read>(log: R) -> Result<ClientAccounts, csv::Error> {
+    let mut balances: BTreeMap<ClientId, (Amount, Amount, bool)> = BTreeMap::new();
+    for entry in csv::Reader::from_reader(log).deserialize() {
+        let entry: AuditEntry = entry?;
+        if entry.result != "ok" {
+            continue;
+        }
+        if let TransactionKind::Close = entry.kind {
+            balances.remove(&entry.client);
+            continue;
+        }
+        let balance = balances
+            .entry(entry.client)
+            .or_insert((Amount::ZERO, Amount::ZERO, false));
+        balance.0 = entry.available_after;
+        balance.1 = entry.held_after;
+        match entry.kind {
+            TransactionKind::Chargeback => balance.2 = true,
+            TransactionKind::Unlock => balance.2 = false,
+            _ => (),
+        }
+    }
+    Ok(ClientAccounts::from_snapshot(balances.into_iter().map(
+        |(client, (available, held, locked))| (client, available, held, locked),
+    )))
+}
@@ -0,0 +1,110 @@
+use {
+    crate::{
+        log_error, process_line, Amount, ClientAccounts, ClientId, Config, Error,
+        MoneyOperationsRegister, ProcessingReport, TransactionId, TransactionKind, TransactionLine,
+    },
+    std::io::{self, Read},
+};
+
+/// Size of a record: 1-byte kind, 4-byte client, 4-byte tx and 8-byte amount,
+/// integers are little-endian and the amount is in ten-thousandths.
+pub const RECORD_LEN: usize = 17;
+
+fn kind_code(kind: TransactionKind) -> u8 {
+    match kind {
+        TransactionKind::Deposit => 0,
+        TransactionKind::Withdrawal => 1,
+        TransactionKind::Dispute => 2,
+        TransactionKind::Resolve => 3,
+        TransactionKind::Chargeback => 4,
+        TransactionKind::Unlock => 5,
+        TransactionKind::Close => 6,
+        TransactionKind::Accrue => 7,
+        TransactionKind::Authorize => 8,
+        TransactionKind::Capture => 9,
+        TransactionKind::Void => 10,
+    }
+}
+
+fn kind_from_code(code: u8) -> Option<TransactionKind> {
+    Some(match code {
+        0 => TransactionKind::Deposit,
+        1 => TransactionKind::Withdrawal,
+        2 => TransactionKind::Dispute,
+        3 => TransactionKind::Resolve,
+        4 => TransactionKind::Chargeback,
+        5 => TransactionKind::Unlock,
+        6 => TransactionKind::Close,
+        7 => TransactionKind::Accrue,
+        8 => TransactionKind::Authorize,
+        9 => TransactionKind::Capture,
+        10 => TransactionKind::Void,
+        _ => return None,
+    })
+}
+
+/// Claims without an amount are written with a zero amount
+pub fn encode_record(
+    kind: TransactionKind,
+    client_id: ClientId,
+    transaction_id: TransactionId,
+    amount: Option<Amount>,
+) -> [u8; RECORD_LEN] {
+    let mut record = [0; RECORD_LEN];
+    record[0] = kind_code(kind);
+    record[1..5].copy_from_slice(&client_id.0.to_le_bytes());
+    record[5..9].copy_from_slice(&transaction_id.0.to_le_bytes());
+    record[9..].copy_from_slice(&amount.unwrap_or(Amount::ZERO).units().to_le_bytes());
+    record
+}
+
+fn decode_record(record: &[u8; RECORD_LEN]) -> Result<TransactionLine, Error> {
+    let kind = kind_from_code(record[0]).ok_or(Error::InvalidKindCode(record[0]))?;
+    let mut client_id = [0; 4];
+    client_id.copy_from_slice(&record[1..5]);
+    let mut transaction_id = [0; 4];
+    transaction_id.copy_from_slice(&record[5..9]);
+    let mut amount = [0; 8];
+    amount.copy_from_slice(&record[9..]);
+    let amount = Amount::from_units(i64::from_le_bytes(amount));
+    let amount = match kind {
+        TransactionKind::Deposit
+        | TransactionKind::Withdrawal
+        | TransactionKind::Accrue
+        | TransactionKind::Authorize => Some(amount),
+        _ => Some(amount).filter(|&amount| amount != Amount::ZERO),
+    };
+    Ok(TransactionLine::new(
+        kind,
+        ClientId(u32::from_le_bytes(client_id)),
+        TransactionId(u32::from_le_bytes(transaction_id)),
+        amount,
+    ))
+}
+
+/// Processes fixed-size records, see `RECORD_LEN`, as the lines of a CSV file.
+/// Rejected records are logged with their 1-based index, a truncated last record
+/// or a read failure stops the processing with an error.
+pub fn read_transactions_binary<R: Read>(
+    mut input: R,
+    accounts: &mut ClientAccounts,
+    operations_register: &mut MoneyOperationsRegister,
+    config: &Config,
+) -> io::Result<ProcessingReport> {
+    let mut report = ProcessingReport::default();
+    let mut record = [0; RECORD_LEN];
+    for index in 1.. {
+        match input.read_exact(&mut record[..1]) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            result => result?,
+        }
+        input.read_exact(&mut record[1..])?;
+        let result = decode_record(&record)
+            .and_then(|line| process_line(line, accounts, operations_register, config, None));
+        report.record(&result);
+        if let Err(e) = &result {
+            log_error(index, e);
+        }
+    }
+    Ok(report)
+}
@@ -1,124 +1,1131 @@
-use crate::TransactionError;
+use crate::{
+    amount::DECIMALS, transactions::TransactionId, Amount, RoundingMode, TransactionError,
+};
 use {
-    serde::{ser::SerializeStruct, Deserialize, Serialize, Serializer},
-    std::{collections::HashMap, io::Write},
+    chrono::{DateTime, Utc},
+    serde::{de, Deserialize, Deserializer, Serialize},
+    std::{
+        collections::{BTreeMap, BTreeSet, HashMap},
+        convert::TryFrom,
+        io::{BufWriter, Read, Write},
+    },
+    strum_macros::{Display, EnumString},
 };
 
-#[derive(Copy, Clone, Debug, Deserialize, Serialize, Eq, PartialEq, Hash)]
+#[derive(Copy, Clone, Debug, Serialize, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub struct ClientId(pub u32);
 
+/// Accepts numbers as well as numeric strings, e.g. map keys or CSV fields
+pub(crate) struct IdVisitor(pub &'static str);
+
+impl<'de> de::Visitor<'de> for IdVisitor {
+    type Value = u32;
+
+    fn expecting(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(fmt, "a numeric {} id", self.0)
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<u32, E> {
+        u32::try_from(v).map_err(|_| E::invalid_value(de::Unexpected::Unsigned(v), &self))
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<u32, E> {
+        u32::try_from(v).map_err(|_| E::invalid_value(de::Unexpected::Signed(v), &self))
+    }
+
+    fn visit_str<E: de::Error>(self, s: &str) -> Result<u32, E> {
+        s.parse()
+            .map_err(|_| E::invalid_value(de::Unexpected::Str(s), &self))
+    }
+}
+
+impl<'de> Deserialize<'de> for ClientId {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer
+            .deserialize_any(IdVisitor("client"))
+            .map(ClientId)
+    }
+}
+
 impl std::fmt::Display for ClientId {
     fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(fmt, "{}", self.0)
     }
 }
 
+/// Currency of an account, empty for the implicit currency of untagged transactions
+#[derive(Clone, Debug, Default, Deserialize, Serialize, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Currency(pub String);
+
+impl Currency {
+    pub fn is_implicit(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl std::fmt::Display for Currency {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(fmt, "{}", self.0)
+    }
+}
+
+/// A client has one account per currency
+#[derive(Clone)]
 pub struct ClientAccounts {
-    inner: HashMap<ClientId, Client>,
+    pub(crate) inner: HashMap<(ClientId, Currency), Client>,
+    /// Funds moved by the applied operations that carry a timestamp
+    pub(crate) history: Vec<(ClientId, Currency, DateTime<Utc>, Amount)>,
+    rounding: RoundingMode,
+    decimals: usize,
+    volume_columns: bool,
+    /// Printed CSV columns, in order, the default ones if `None`
+    columns: Option<Vec<SummaryColumn>>,
+}
+
+impl Default for ClientAccounts {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ClientAccounts {
     pub fn new() -> ClientAccounts {
         ClientAccounts {
             inner: HashMap::new(),
+            history: Vec::new(),
+            rounding: RoundingMode::default(),
+            decimals: DECIMALS,
+            volume_columns: false,
+            columns: None,
+        }
+    }
+    /// Reads the CSV output of a previous run, the total and volume columns are ignored
+    pub fn read_summaries<R: Read>(r: R) -> Result<ClientAccounts, csv::Error> {
+        #[derive(Deserialize)]
+        struct SummaryRow {
+            client: ClientId,
+            #[serde(default)]
+            currency: Currency,
+            available: Amount,
+            held: Amount,
+            locked: bool,
+        }
+
+        let mut accounts = ClientAccounts::new();
+        for row in csv::Reader::from_reader(r).deserialize() {
+            let row: SummaryRow = row?;
+            accounts.create_account(row.client, row.currency.clone(), row.available);
+            if let Some(client) = accounts.get_currency_account(row.client, &row.currency) {
+                client.held_funds = row.held;
+                client.locked = row.locked;
+            }
+        }
+        Ok(accounts)
+    }
+    /// Seeds the accounts from (client, available, held, locked) entries
+    pub fn from_snapshot(
+        snapshot: impl IntoIterator<Item = (ClientId, Amount, Amount, bool)>,
+    ) -> ClientAccounts {
+        let mut accounts = ClientAccounts::new();
+        for (id, funds, held_funds, locked) in snapshot {
+            accounts.create_client(id, funds);
+            if let Some(client) = accounts.get_account(id) {
+                client.held_funds = held_funds;
+                client.locked = locked;
+            }
+        }
+        accounts
+    }
+    /// Rounding applied to the printed amounts
+    pub fn set_rounding_mode(&mut self, rounding: RoundingMode) {
+        self.rounding = rounding;
+    }
+    /// Decimal places of the printed amounts, at most the four decimals amounts
+    /// are kept with
+    pub fn set_decimals(&mut self, decimals: usize) -> Result<(), TooManyDecimals> {
+        if decimals > DECIMALS {
+            return Err(TooManyDecimals(decimals));
+        }
+        self.decimals = decimals;
+        Ok(())
+    }
+    /// Adds the deposited and withdrawn columns to the printed summaries
+    pub fn set_volume_columns(&mut self, volume_columns: bool) {
+        self.volume_columns = volume_columns;
+    }
+    /// Restricts the CSV output to these columns, in this order
+    pub fn set_columns(&mut self, columns: Vec<SummaryColumn>) -> Result<(), DuplicatedColumn> {
+        for (i, column) in columns.iter().enumerate() {
+            if columns[..i].contains(column) {
+                return Err(DuplicatedColumn(*column));
+            }
         }
+        self.columns = Some(columns);
+        Ok(())
     }
+    /// Account of the client in the implicit currency
     pub fn get_account(&mut self, client_id: ClientId) -> Option<&mut Client> {
-        self.inner.get_mut(&client_id)
+        self.get_currency_account(client_id, &Currency::default())
+    }
+    pub fn get_currency_account(
+        &mut self,
+        client_id: ClientId,
+        currency: &Currency,
+    ) -> Option<&mut Client> {
+        self.inner.get_mut(&(client_id, currency.clone()))
+    }
+    /// Accounts of the client in every currency
+    pub fn accounts_of(&mut self, client_id: ClientId) -> impl Iterator<Item = &mut Client> {
+        self.inner
+            .iter_mut()
+            .filter(move |((id, _), _)| *id == client_id)
+            .map(|(_, client)| client)
+    }
+    /// Removes the accounts of the client in every currency
+    pub fn remove_client(&mut self, client_id: ClientId) {
+        self.inner.retain(|(id, _), _| *id != client_id);
+    }
+    pub fn iter(&self) -> impl Iterator<Item = (&ClientId, &Client)> {
+        self.inner.iter().map(|((id, _), client)| (id, client))
+    }
+    pub fn balance_of(&self, client_id: ClientId) -> Option<AccountSummary> {
+        self.inner
+            .get(&(client_id, Currency::default()))
+            .map(Client::summary)
+    }
+    /// Available and held funds of an account, zero if the account doesn't exist
+    pub fn balances(&self, client_id: ClientId, currency: &Currency) -> (Amount, Amount) {
+        self.inner
+            .get(&(client_id, currency.clone()))
+            .map_or((Amount::ZERO, Amount::ZERO), |client| {
+                (client.funds, client.held_funds)
+            })
     }
-    pub fn create_client(&mut self, id: ClientId, funds: f64) {
+    /// Adds funds to an account, creating it if needed
+    pub fn credit(
+        &mut self,
+        id: ClientId,
+        currency: &Currency,
+        amount: Amount,
+    ) -> Result<(), TransactionError> {
+        match self.get_currency_account(id, currency) {
+            Some(client) => client.increase_funds(amount),
+            None => {
+                self.create_account(id, currency.clone(), amount);
+                Ok(())
+            }
+        }
+    }
+    pub fn create_client(&mut self, id: ClientId, funds: Amount) {
+        self.create_account(id, Currency::default(), funds)
+    }
+    pub fn create_account(&mut self, id: ClientId, currency: Currency, funds: Amount) {
         self.inner.insert(
-            id,
+            (id, currency.clone()),
             Client {
+                id,
+                currency,
                 funds,
-                held_funds: 0.,
+                held_funds: Amount::ZERO,
+                deposited: Amount::ZERO,
+                withdrawn: Amount::ZERO,
                 locked: false,
+                lock_reason: None,
+                held_entries: Vec::new(),
+                metadata: HashMap::new(),
             },
         );
     }
+    /// Adds the accounts of another set of clients. An account found in both, like the
+    /// fee collector credited by several shards, gets the sum of their balances.
+    pub fn merge(&mut self, other: ClientAccounts) -> Result<(), TransactionError> {
+        for (key, client) in other.inner {
+            match self.inner.get_mut(&key) {
+                Some(existing) => existing.absorb(client)?,
+                None => {
+                    self.inner.insert(key, client);
+                }
+            }
+        }
+        self.history.extend(other.history);
+        Ok(())
+    }
+    /// Records funds moved at a given time, negative for a withdrawal
+    pub fn record_dated(
+        &mut self,
+        client_id: ClientId,
+        currency: Currency,
+        timestamp: DateTime<Utc>,
+        amount: Amount,
+    ) {
+        self.history.push((client_id, currency, timestamp, amount));
+    }
+    /// Net funds moved by the dated deposits and withdrawals between `start` (included)
+    /// and `end` (excluded), one summary per account sorted by client and currency.
+    /// Disputes are not taken into account.
+    pub fn summary_between(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<AccountSummary>, TotalsOverflow> {
+        let mut moved: HashMap<(ClientId, &Currency), Amount> = HashMap::new();
+        for (client_id, currency, _, amount) in self
+            .history
+            .iter()
+            .filter(|(_, _, timestamp, _)| start <= *timestamp && *timestamp < end)
+        {
+            let sum = moved.entry((*client_id, currency)).or_default();
+            *sum = sum.checked_add(*amount).ok_or(TotalsOverflow)?;
+        }
+        let tagged = self.tagged();
+        let mut summaries: Vec<AccountSummary> = moved
+            .into_iter()
+            .map(|((client, currency), amount)| AccountSummary {
+                client,
+                currency: tagged.then(|| currency.clone()),
+                available: amount,
+                held: Amount::ZERO,
+                total: amount,
+                locked: self
+                    .inner
+                    .get(&(client, currency.clone()))
+                    .is_some_and(|account| account.locked),
+                deposited: None,
+                withdrawn: None,
+            })
+            .collect();
+        summaries.sort_by(|a, b| (a.client, &a.currency).cmp(&(b.client, &b.currency)));
+        Ok(summaries)
+    }
+    /// Checks that every account is internally consistent: the total can be computed
+    /// without overflowing and held funds are never negative.
+    pub fn verify_invariants(&self) -> Result<(), Vec<InvariantViolation>> {
+        let mut violations: Vec<InvariantViolation> = self
+            .inner
+            .values()
+            .flat_map(|client| {
+                let overflow = client
+                    .funds
+                    .checked_add(client.held_funds)
+                    .is_none()
+                    .then_some(InvariantViolation::TotalOverflow(client.id));
+                let negative_held = (client.held_funds < Amount::ZERO)
+                    .then_some(InvariantViolation::NegativeHeld(client.id));
+                overflow.into_iter().chain(negative_held)
+            })
+            .collect();
+        if violations.is_empty() {
+            return Ok(());
+        }
+        violations.sort_by_key(|violation| violation.client_id());
+        Err(violations)
+    }
+    /// Negative balances, which disputes of spent funds can legitimately produce,
+    /// sorted by client.
+    pub fn anomalies(&self) -> Vec<(ClientId, AnomalyKind)> {
+        let mut clients: Vec<&Client> = self.inner.values().collect();
+        clients.sort_by(|a, b| (a.id, &a.currency).cmp(&(b.id, &b.currency)));
+        clients
+            .into_iter()
+            .flat_map(|client| {
+                let total = client.funds.checked_add(client.held_funds);
+                vec![
+                    (client.funds < Amount::ZERO).then_some(AnomalyKind::NegativeAvailable),
+                    (client.held_funds < Amount::ZERO).then_some(AnomalyKind::NegativeHeld),
+                    matches!(total, Some(total) if total < Amount::ZERO)
+                        .then_some(AnomalyKind::NegativeTotal),
+                ]
+                .into_iter()
+                .flatten()
+                .map(move |kind| (client.id, kind))
+            })
+            .collect()
+    }
+    /// Sums of the balances of the accounts of each currency
+    pub fn platform_totals(&self) -> Result<BTreeMap<Currency, PlatformTotals>, TotalsOverflow> {
+        let mut platform_totals: BTreeMap<Currency, PlatformTotals> = BTreeMap::new();
+        for client in self.inner.values() {
+            let totals = platform_totals.entry(client.currency.clone()).or_default();
+            totals.available = totals
+                .available
+                .checked_add(client.funds)
+                .ok_or(TotalsOverflow)?;
+            totals.held = totals
+                .held
+                .checked_add(client.held_funds)
+                .ok_or(TotalsOverflow)?;
+            totals.total = client
+                .funds
+                .checked_add(client.held_funds)
+                .and_then(|total| totals.total.checked_add(total))
+                .ok_or(TotalsOverflow)?;
+            if client.locked {
+                totals.locked_accounts += 1;
+            }
+        }
+        Ok(platform_totals)
+    }
+    /// Footer rows of the CSV output, one per currency, in the same columns and
+    /// rounding as the accounts
+    pub fn print_totals_to<W: Write>(
+        &self,
+        platform_totals: &BTreeMap<Currency, PlatformTotals>,
+        w: &mut W,
+    ) -> Result<(), csv::Error> {
+        let columns = self
+            .columns
+            .clone()
+            .unwrap_or_else(|| self.default_columns());
+        let metadata_columns = self.metadata_columns();
+        let mut writer = csv::WriterBuilder::new().has_headers(false).from_writer(w);
+        for (currency, totals) in platform_totals {
+            let totals = totals.rounded(self.decimals, self.rounding);
+            writer.write_record(
+                columns
+                    .iter()
+                    .map(|column| match column {
+                        SummaryColumn::Client => String::from("total"),
+                        SummaryColumn::Currency => currency.to_string(),
+                        SummaryColumn::Available => totals.available.format(self.decimals),
+                        SummaryColumn::Held => totals.held.format(self.decimals),
+                        SummaryColumn::Total => totals.total.format(self.decimals),
+                        SummaryColumn::Locked => totals.locked_accounts.to_string(),
+                        SummaryColumn::Deposited | SummaryColumn::Withdrawn => String::new(),
+                    })
+                    .chain(metadata_columns.iter().map(|_| String::new())),
+            )?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+    /// Totals as JSON objects, one per currency, with the rounding of the accounts
+    pub fn print_totals_json_to<W: Write>(
+        &self,
+        platform_totals: &BTreeMap<Currency, PlatformTotals>,
+        w: &mut W,
+    ) -> std::io::Result<()> {
+        for (currency, totals) in platform_totals {
+            totals.rounded(self.decimals, self.rounding).write_json_to(
+                w,
+                currency,
+                self.decimals,
+            )?;
+        }
+        Ok(())
+    }
+    /// Accounts locked by a chargeback, with the charged back transaction, sorted by client
+    pub fn locked_accounts(&self) -> Vec<(ClientId, TransactionId)> {
+        let mut locked: Vec<(ClientId, TransactionId)> = self
+            .inner
+            .values()
+            .filter(|client| client.locked)
+            .filter_map(|client| Some((client.id, client.locked_by()?)))
+            .collect();
+        locked.sort_by_key(|(client_id, _)| *client_id);
+        locked
+    }
+    /// The header is written even when there are no clients.
+    /// The CSV writer is buffered and flushed before returning, so that a failed
+    /// write, e.g. to a closed pipe, is reported instead of being lost on drop.
     pub fn print_to<W: Write>(&self, w: &mut W) -> Result<(), csv::Error> {
         let mut writer = csv::Writer::from_writer(w);
-        for (id, account) in &self.inner {
-            writer.serialize(AccountSummary {
-                client: *id,
-                available: account.funds,
-                held: account.held_funds,
-                locked: account.locked,
-                total: account.held_funds + account.funds,
-            })?
+        let metadata_columns = self.metadata_columns();
+        let columns = self
+            .columns
+            .clone()
+            .unwrap_or_else(|| self.default_columns());
+        writer.write_record(
+            columns
+                .iter()
+                .map(SummaryColumn::to_string)
+                .chain(metadata_columns.iter().cloned()),
+        )?;
+        for summary in self.summaries() {
+            let metadata = self
+                .inner
+                .get(&(summary.client, summary.currency.clone().unwrap_or_default()))
+                .map(|client| &client.metadata);
+            writer.write_record(
+                columns
+                    .iter()
+                    .map(|&column| summary.field(column, self.decimals))
+                    .chain(metadata_columns.iter().map(|column| {
+                        metadata
+                            .and_then(|metadata| metadata.get(column))
+                            .cloned()
+                            .unwrap_or_default()
+                    })),
+            )?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+    /// Writes the rows of the accounts of a client, without header, e.g. to follow
+    /// a client after each of its transactions. The columns are the ones of `print_to`,
+    /// metadata columns included.
+    pub fn print_client_to<W: Write>(
+        &self,
+        client_id: ClientId,
+        w: &mut W,
+    ) -> Result<(), csv::Error> {
+        let mut writer = csv::WriterBuilder::new().has_headers(false).from_writer(w);
+        let tagged = self.tagged();
+        let mut accounts: Vec<&Client> = self
+            .inner
+            .values()
+            .filter(|client| client.id == client_id)
+            .collect();
+        accounts.sort_by(|a, b| a.currency.cmp(&b.currency));
+        let columns = self
+            .columns
+            .clone()
+            .unwrap_or_else(|| self.default_columns());
+        let metadata_columns = self.metadata_columns();
+        for client in accounts {
+            let summary = self.printed_summary(client, tagged);
+            writer.write_record(
+                columns
+                    .iter()
+                    .map(|&column| summary.field(column, self.decimals))
+                    .chain(
+                        metadata_columns
+                            .iter()
+                            .map(|column| client.metadata.get(column).cloned().unwrap_or_default()),
+                    ),
+            )?
         }
+        writer.flush()?;
         Ok(())
     }
+    /// Writes one JSON object per line for each client.
+    pub fn print_json_to<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        let mut w = BufWriter::new(w);
+        for summary in self.summaries() {
+            summary.write_json_to(&mut w, self.decimals)?;
+            writeln!(w)?
+        }
+        w.flush()
+    }
+    /// Writes a single JSON array of the clients.
+    pub fn print_json_array_to<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        let mut w = BufWriter::new(w);
+        write!(w, "[")?;
+        for (i, summary) in self.summaries().iter().enumerate() {
+            if i > 0 {
+                write!(w, ",")?;
+            }
+            summary.write_json_to(&mut w, self.decimals)?;
+        }
+        writeln!(w, "]")?;
+        w.flush()
+    }
+    /// Summaries sorted by client id and currency, so that the output is deterministic.
+    /// The currency column is only added when some accounts are tagged.
+    fn summaries(&self) -> Vec<AccountSummary> {
+        let tagged = self.tagged();
+        let mut summaries: Vec<AccountSummary> = self
+            .inner
+            .values()
+            .map(|client| self.printed_summary(client, tagged))
+            .collect();
+        summaries.sort_by(|a, b| (a.client, &a.currency).cmp(&(b.client, &b.currency)));
+        summaries
+    }
+    /// Columns of the CSV output when none are selected
+    fn default_columns(&self) -> Vec<SummaryColumn> {
+        let mut columns = vec![SummaryColumn::Client];
+        if self.tagged() {
+            columns.push(SummaryColumn::Currency);
+        }
+        columns.extend(vec![
+            SummaryColumn::Available,
+            SummaryColumn::Held,
+            SummaryColumn::Total,
+            SummaryColumn::Locked,
+        ]);
+        if self.volume_columns {
+            columns.extend(vec![SummaryColumn::Deposited, SummaryColumn::Withdrawn]);
+        }
+        columns
+    }
+    /// Metadata columns of all the clients, sorted by name
+    fn metadata_columns(&self) -> Vec<String> {
+        let columns: BTreeSet<&String> = self
+            .inner
+            .values()
+            .flat_map(|client| client.metadata.keys())
+            .collect();
+        columns.into_iter().cloned().collect()
+    }
+    /// Keeps the first metadata of the account with a non-empty value
+    pub(crate) fn attach_metadata(
+        &mut self,
+        client_id: ClientId,
+        currency: &Currency,
+        metadata: HashMap<String, String>,
+    ) {
+        if metadata.values().all(String::is_empty) {
+            return;
+        }
+        if let Some(client) = self.get_currency_account(client_id, currency) {
+            if client.metadata.is_empty() {
+                client.metadata = metadata;
+            }
+        }
+    }
+    fn tagged(&self) -> bool {
+        self.inner
+            .keys()
+            .any(|(_, currency)| !currency.is_implicit())
+    }
+    /// Summary with the columns and the rounding of the output
+    fn printed_summary(&self, client: &Client, tagged: bool) -> AccountSummary {
+        let mut summary = client.summary();
+        if tagged {
+            summary.currency = Some(client.currency.clone());
+        }
+        if self.volume_columns || self.volume_selected() {
+            let (deposited, withdrawn) = client.volume();
+            summary.deposited = Some(deposited);
+            summary.withdrawn = Some(withdrawn);
+        }
+        summary.rounded(self.decimals, self.rounding)
+    }
+    fn volume_selected(&self) -> bool {
+        self.columns.as_ref().is_some_and(|columns| {
+            columns
+                .iter()
+                .any(|column| matches!(column, SummaryColumn::Deposited | SummaryColumn::Withdrawn))
+        })
+    }
 }
 
-#[derive(Debug)]
+/// Serialized with the same field names as the account summary, amounts keep
+/// their four decimals so that a reloaded client is exactly the same.
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Client {
-    funds: f64,
-    held_funds: f64,
+    #[serde(rename = "client", alias = "id")]
+    pub(crate) id: ClientId,
+    #[serde(default)]
+    pub(crate) currency: Currency,
+    #[serde(rename = "available", alias = "funds")]
+    funds: Amount,
+    #[serde(rename = "held", alias = "held_funds")]
+    held_funds: Amount,
+    deposited: Amount,
+    withdrawn: Amount,
     pub locked: bool,
+    #[serde(default)]
+    lock_reason: Option<LockReason>,
+    /// Funds held by each disputed transaction, in dispute order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    held_entries: Vec<(TransactionId, Amount)>,
+    /// Extra columns of the first transaction of the client, added to the CSV output
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    metadata: HashMap<String, String>,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum InvariantViolation {
+    NegativeHeld(ClientId),
+    TotalOverflow(ClientId),
+}
+
+impl InvariantViolation {
+    pub fn client_id(&self) -> ClientId {
+        match *self {
+            Self::NegativeHeld(client_id) | Self::TotalOverflow(client_id) => client_id,
+        }
+    }
+}
+
+impl std::fmt::Display for InvariantViolation {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::NegativeHeld(client_id) => {
+                write!(fmt, "Client {} has negative held funds", client_id)
+            }
+            Self::TotalOverflow(client_id) => {
+                write!(fmt, "Total funds of client {} overflow", client_id)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct PlatformTotals {
+    pub available: Amount,
+    pub held: Amount,
+    pub total: Amount,
+    pub locked_accounts: usize,
+}
+
+impl PlatformTotals {
+    pub fn rounded(&self, decimals: usize, rounding: RoundingMode) -> Self {
+        Self {
+            available: self.available.round(decimals, rounding),
+            held: self.held.round(decimals, rounding),
+            total: self.total.round(decimals, rounding),
+            locked_accounts: self.locked_accounts,
+        }
+    }
+    /// Same fields as the JSON account summaries, the locked accounts are counted.
+    /// The currency is left out for the implicit currency.
+    pub fn write_json_to<W: Write>(
+        &self,
+        w: &mut W,
+        currency: &Currency,
+        decimals: usize,
+    ) -> std::io::Result<()> {
+        write!(w, "{{")?;
+        if !currency.is_implicit() {
+            let currency = serde_json::Value::from(currency.0.as_str());
+            write!(w, r#""currency":{},"#, currency)?;
+        }
+        writeln!(
+            w,
+            r#""available":{},"held":{},"total":{},"locked_accounts":{}}}"#,
+            self.available.format(decimals),
+            self.held.format(decimals),
+            self.total.format(decimals),
+            self.locked_accounts
+        )
+    }
+}
+
+/// Footer row of the CSV output, in the columns of the account summaries
+impl std::fmt::Display for PlatformTotals {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            fmt,
+            "total,{},{},{},{}",
+            self.available, self.held, self.total, self.locked_accounts
+        )
+    }
+}
+
+/// Change of an account between two runs
+#[derive(Debug, Eq, PartialEq, Serialize)]
+pub struct BalanceDelta {
+    pub client: ClientId,
+    pub currency: Currency,
+    pub change: DeltaKind,
+    pub available: Amount,
+    pub held: Amount,
+    /// Lock state in the new run, if it changed
+    pub locked: Option<bool>,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeltaKind {
+    Appeared,
+    Disappeared,
+    Changed,
+}
+
+/// Changes of the accounts from `old` to `new`, sorted by client and currency.
+/// Missing accounts count as empty and unlocked, unchanged ones are left out.
+pub fn diff_summaries(
+    old: &ClientAccounts,
+    new: &ClientAccounts,
+) -> Result<Vec<BalanceDelta>, DeltaOverflow> {
+    let balance = |client: Option<&Client>| {
+        client.map_or((Amount::ZERO, Amount::ZERO, false), |client| {
+            (client.funds, client.held_funds, client.locked)
+        })
+    };
+    let keys: BTreeSet<&(ClientId, Currency)> = old.inner.keys().chain(new.inner.keys()).collect();
+    keys.into_iter()
+        .filter_map(|key| {
+            let (old_client, new_client) = (old.inner.get(key), new.inner.get(key));
+            let change = match (old_client, new_client) {
+                (None, _) => DeltaKind::Appeared,
+                (_, None) => DeltaKind::Disappeared,
+                _ => DeltaKind::Changed,
+            };
+            let (old_available, old_held, old_locked) = balance(old_client);
+            let (new_available, new_held, new_locked) = balance(new_client);
+            if change == DeltaKind::Changed
+                && (old_available, old_held, old_locked) == (new_available, new_held, new_locked)
+            {
+                return None;
+            }
+            let overflow = DeltaOverflow(key.0);
+            Some(Ok(BalanceDelta {
+                client: key.0,
+                currency: key.1.clone(),
+                change,
+                available: match new_available.checked_sub(old_available) {
+                    Some(available) => available,
+                    None => return Some(Err(overflow)),
+                },
+                held: match new_held.checked_sub(old_held) {
+                    Some(held) => held,
+                    None => return Some(Err(overflow)),
+                },
+                locked: (new_locked != old_locked).then_some(new_locked),
+            }))
+        })
+        .collect()
+}
+
+/// Column of the CSV output
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Display, EnumString)]
+#[strum(serialize_all = "lowercase")]
+pub enum SummaryColumn {
+    Client,
+    Currency,
+    Available,
+    Held,
+    Total,
+    Locked,
+    Deposited,
+    Withdrawn,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct DuplicatedColumn(pub SummaryColumn);
+
+impl std::fmt::Display for DuplicatedColumn {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(fmt, "Column {} is selected more than once", self.0)
+    }
+}
+
+impl std::error::Error for DuplicatedColumn {}
+
+/// More decimal places than the amounts are kept with
+#[derive(Debug, Eq, PartialEq)]
+pub struct TooManyDecimals(pub usize);
+
+impl std::fmt::Display for TooManyDecimals {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            fmt,
+            "Amounts have at most {} decimals, {} can't be printed",
+            DECIMALS, self.0
+        )
+    }
+}
+
+impl std::error::Error for TooManyDecimals {}
+
+/// The sum of the balances, or of the funds moved in a time window, doesn't fit
+/// in an amount
+#[derive(Debug, Eq, PartialEq)]
+pub struct TotalsOverflow;
+
+impl std::fmt::Display for TotalsOverflow {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(fmt, "Totals overflow")
+    }
+}
+
+impl std::error::Error for TotalsOverflow {}
+
+/// The change of a client balance doesn't fit in an amount
+#[derive(Debug, Eq, PartialEq)]
+pub struct DeltaOverflow(pub ClientId);
+
+impl std::fmt::Display for DeltaOverflow {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(fmt, "Balance change of client {} overflows", self.0)
+    }
+}
+
+impl std::error::Error for DeltaOverflow {}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum AnomalyKind {
+    NegativeAvailable,
+    NegativeHeld,
+    NegativeTotal,
+}
+
+impl std::fmt::Display for AnomalyKind {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let balance = match self {
+            Self::NegativeAvailable => "available",
+            Self::NegativeHeld => "held",
+            Self::NegativeTotal => "total",
+        };
+        write!(fmt, "negative {} funds", balance)
+    }
+}
+
+/// Why an account got locked
+#[derive(Copy, Clone, Debug, Deserialize, Serialize, Eq, PartialEq)]
+pub enum LockReason {
+    Chargeback(TransactionId),
 }
 
 impl Client {
-    pub fn increase_funds(&mut self, amount: f64) {
-        self.funds += amount;
+    pub fn summary(&self) -> AccountSummary {
+        AccountSummary {
+            client: self.id,
+            currency: None,
+            available: self.funds,
+            held: self.held_funds,
+            locked: self.locked,
+            // Every operation keeps the total within bounds, see `verify_invariants`
+            total: self
+                .held_funds
+                .checked_add(self.funds)
+                .unwrap_or(Amount::MAX),
+            deposited: None,
+            withdrawn: None,
+        }
+    }
+    pub fn available(&self) -> Amount {
+        self.funds
+    }
+    /// Adds the balances and volume of the same account kept apart
+    fn absorb(&mut self, other: Client) -> Result<(), TransactionError> {
+        match (
+            self.funds.checked_add(other.funds),
+            self.held_funds.checked_add(other.held_funds),
+        ) {
+            (Some(funds), Some(held_funds)) if funds.checked_add(held_funds).is_some() => {
+                self.funds = funds;
+                self.held_funds = held_funds;
+            }
+            _ => return Err(TransactionError::Overflow(self.id)),
+        }
+        self.record_deposit(other.deposited);
+        self.record_withdrawal(other.withdrawn);
+        if other.locked && !self.locked {
+            self.locked = true;
+            self.lock_reason = other.lock_reason;
+        }
+        self.held_entries.extend(other.held_entries);
+        if self.metadata.is_empty() {
+            self.metadata = other.metadata;
+        }
+        Ok(())
+    }
+    pub fn held(&self) -> Amount {
+        self.held_funds
+    }
+    /// Disputed transactions contributing to the held funds, with their held amount
+    pub fn held_entries(&self) -> &[(TransactionId, Amount)] {
+        &self.held_entries
+    }
+    /// Partial disputes of the same transaction add up in a single entry, `held` is
+    /// the amount the transaction holds so far
+    pub(crate) fn set_held_entry(&mut self, transaction_id: TransactionId, held: Amount) {
+        match self
+            .held_entries
+            .iter_mut()
+            .find(|(id, _)| *id == transaction_id)
+        {
+            Some((_, entry)) => *entry = held,
+            None => self.held_entries.push((transaction_id, held)),
+        }
+    }
+    pub(crate) fn remove_held_entry(&mut self, transaction_id: TransactionId) {
+        self.held_entries.retain(|(id, _)| *id != transaction_id);
+    }
+    /// Lifetime deposited and withdrawn amounts, disputes don't change them
+    pub fn volume(&self) -> (Amount, Amount) {
+        (self.deposited, self.withdrawn)
+    }
+    pub fn record_deposit(&mut self, amount: Amount) {
+        self.deposited = self.deposited.checked_add(amount).unwrap_or(Amount::MAX);
+    }
+    pub fn record_withdrawal(&mut self, amount: Amount) {
+        self.withdrawn = self.withdrawn.checked_add(amount).unwrap_or(Amount::MAX);
+    }
+    /// The total balance (available + held) can't exceed `Amount::MAX`.
+    pub fn increase_funds(&mut self, amount: Amount) -> Result<(), TransactionError> {
+        match self.funds.checked_add(amount) {
+            Some(funds) if funds.checked_add(self.held_funds).is_some() => {
+                self.funds = funds;
+                Ok(())
+            }
+            _ => Err(TransactionError::Overflow(self.id)),
+        }
+    }
+    /// Even with an overdraft, the balance can't go below the smallest amount.
+    pub fn decrease_funds(&mut self, amount: Amount) -> Result<(), TransactionError> {
+        self.funds = self
+            .funds
+            .checked_sub(amount)
+            .ok_or(TransactionError::Overflow(self.id))?;
+        Ok(())
     }
-    pub fn decrease_funds(&mut self, amount: f64) {
-        self.funds -= amount;
+    #[cfg(test)]
+    pub(crate) fn set_held(&mut self, amount: Amount) {
+        self.held_funds = amount;
     }
-    pub fn has_enough_funds(&self, amount: f64) -> bool {
+    pub fn lock(&mut self, reason: LockReason) {
+        self.locked = true;
+        self.lock_reason = Some(reason);
+    }
+    pub fn unlock(&mut self) {
+        self.locked = false;
+        self.lock_reason = None;
+    }
+    pub fn lock_reason(&self) -> Option<LockReason> {
+        self.lock_reason
+    }
+    /// Transaction whose chargeback locked the account
+    pub fn locked_by(&self) -> Option<TransactionId> {
+        match self.lock_reason? {
+            LockReason::Chargeback(transaction_id) => Some(transaction_id),
+        }
+    }
+    /// Amounts are exact decimals, so no tolerance is needed: withdrawing the whole
+    /// balance always succeeds.
+    pub fn has_enough_funds(&self, amount: Amount) -> bool {
         self.funds >= amount
     }
-    pub fn hold_funds(&mut self, amount: f64) {
-        self.funds -= amount;
-        self.held_funds += amount;
+    /// Moves funds from available to held, the total is unchanged.
+    pub fn hold_funds(&mut self, amount: Amount) -> Result<(), TransactionError> {
+        match (
+            self.funds.checked_sub(amount),
+            self.held_funds.checked_add(amount),
+        ) {
+            (Some(funds), Some(held_funds)) => {
+                self.funds = funds;
+                self.held_funds = held_funds;
+                Ok(())
+            }
+            _ => Err(TransactionError::Overflow(self.id)),
+        }
+    }
+    /// Holds money coming back into the account, as for a disputed withdrawal.
+    pub fn hold_returned_funds(&mut self, amount: Amount) -> Result<(), TransactionError> {
+        match self.held_funds.checked_add(amount) {
+            Some(held_funds) if held_funds.checked_add(self.funds).is_some() => {
+                self.held_funds = held_funds;
+                Ok(())
+            }
+            _ => Err(TransactionError::Overflow(self.id)),
+        }
+    }
+    /// Adds interest at `rate` to the funds held by each dispute, returning the new
+    /// held entries. The interest is held with the dispute that earned it, so that
+    /// its resolve or chargeback releases it too.
+    pub fn accrue_held(
+        &mut self,
+        rate: Amount,
+    ) -> Result<Vec<(TransactionId, Amount)>, TransactionError> {
+        for index in 0..self.held_entries.len() {
+            let (_, held) = self.held_entries[index];
+            let interest = held
+                .checked_mul(rate)
+                .ok_or(TransactionError::Overflow(self.id))?;
+            self.hold_returned_funds(interest)?;
+            self.held_entries[index].1 = held
+                .checked_add(interest)
+                .ok_or(TransactionError::Overflow(self.id))?;
+        }
+        Ok(self.held_entries.clone())
     }
-    pub fn clear_held_funds(&mut self, amount: f64) -> Result<(), TransactionError> {
+    /// Held funds are exact like the available ones: releasing what a dispute held
+    /// never falls short, whatever happened to the other held amounts in between.
+    pub fn clear_held_funds(&mut self, amount: Amount) -> Result<(), TransactionError> {
         if self.held_funds < amount {
             return Err(TransactionError::NotEnoughFunds);
         }
-        self.held_funds -= amount;
+        self.held_funds = self
+            .held_funds
+            .checked_sub(amount)
+            .ok_or(TransactionError::Overflow(self.id))?;
         Ok(())
     }
-    pub fn release_funds(&mut self, amount: f64) -> Result<(), TransactionError> {
+    pub fn release_funds(&mut self, amount: Amount) -> Result<(), TransactionError> {
         if self.held_funds < amount {
             return Err(TransactionError::NotEnoughFunds);
         }
-        self.held_funds -= amount;
-        self.funds += amount;
-        Ok(())
+        match (
+            self.held_funds.checked_sub(amount),
+            self.funds.checked_add(amount),
+        ) {
+            (Some(held_funds), Some(funds)) => {
+                self.held_funds = held_funds;
+                self.funds = funds;
+                Ok(())
+            }
+            _ => Err(TransactionError::Overflow(self.id)),
+        }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, PartialEq)]
 pub struct AccountSummary {
-    client: ClientId,
-    available: f64,
-    held: f64,
-    total: f64,
-    locked: bool,
-}
-
-trait FourDigitsPrecision {
-    fn four_digits_precision(self) -> Self;
+    pub client: ClientId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub currency: Option<Currency>,
+    pub available: Amount,
+    pub held: Amount,
+    pub total: Amount,
+    pub locked: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deposited: Option<Amount>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub withdrawn: Option<Amount>,
 }
 
-impl FourDigitsPrecision for f64 {
-    fn four_digits_precision(self) -> Self {
-        (self * 10000.).round() / 10000.
+impl AccountSummary {
+    pub fn rounded(self, decimals: usize, rounding: RoundingMode) -> Self {
+        Self {
+            available: self.available.round(decimals, rounding),
+            held: self.held.round(decimals, rounding),
+            total: self.total.round(decimals, rounding),
+            deposited: self
+                .deposited
+                .map(|amount| amount.round(decimals, rounding)),
+            withdrawn: self
+                .withdrawn
+                .map(|amount| amount.round(decimals, rounding)),
+            ..self
+        }
     }
-}
-
-impl Serialize for AccountSummary {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: Serializer,
-    {
-        let mut state = serializer.serialize_struct("AccountSummary", 5)?;
-        state.serialize_field("client", &self.client)?;
-        state.serialize_field("available", &(self.available.four_digits_precision()))?;
-        state.serialize_field("held", &self.held.four_digits_precision())?;
-        state.serialize_field("total", &self.total.four_digits_precision())?;
-        state.serialize_field("locked", &self.locked)?;
-        state.end()
+    /// Field of a summary rounded to `decimals` places
+    fn field(&self, column: SummaryColumn, decimals: usize) -> String {
+        let format = |amount: Amount| amount.format(decimals);
+        match column {
+            SummaryColumn::Client => self.client.to_string(),
+            SummaryColumn::Currency => self
+                .currency
+                .as_ref()
+                .map_or_else(String::new, Currency::to_string),
+            SummaryColumn::Available => format(self.available),
+            SummaryColumn::Held => format(self.held),
+            SummaryColumn::Total => format(self.total),
+            SummaryColumn::Locked => self.locked.to_string(),
+            SummaryColumn::Deposited => self.deposited.map_or_else(String::new, format),
+            SummaryColumn::Withdrawn => self.withdrawn.map_or_else(String::new, format),
+        }
+    }
+    /// Amounts are written as JSON numbers, with the same precision as the CSV output
+    fn write_json_to<W: Write>(&self, w: &mut W, decimals: usize) -> std::io::Result<()> {
+        write!(w, r#"{{"client":{},"#, self.client)?;
+        if let Some(currency) = &self.currency {
+            let currency = serde_json::Value::from(currency.0.as_str());
+            write!(w, r#""currency":{},"#, currency)?;
+        }
+        write!(
+            w,
+            r#""available":{},"held":{},"total":{},"locked":{}"#,
+            self.available.format(decimals),
+            self.held.format(decimals),
+            self.total.format(decimals),
+            self.locked
+        )?;
+        if let (Some(deposited), Some(withdrawn)) = (self.deposited, self.withdrawn) {
+            write!(
+                w,
+                r#","deposited":{},"withdrawn":{}"#,
+                deposited.format(decimals),
+                withdrawn.format(decimals)
+            )?;
+        }
+        write!(w, "}}")
     }
 }
@@ -0,0 +1,154 @@
+use {
+    crate::{Amount, ClientId, TransactionKind},
+    serde::{Deserialize, Serialize},
+    std::collections::HashSet,
+    strum::IntoEnumIterator,
+    strum_macros::EnumString,
+};
+
+/// Processing rules that can be tuned by the caller, the default follows the spec.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Maximum number of disputes on a single transaction, unlimited if `None`
+    pub max_disputes: Option<u32>,
+    /// Highest accepted client id, unlimited if `None`
+    pub max_client_id: Option<u32>,
+    /// Field delimiter of the input CSV
+    pub delimiter: u8,
+    /// Decimal separator of the amounts, e.g. ',' with a ';' delimiter
+    pub decimal_separator: char,
+    /// Stop at the first line that can't be read instead of rejecting it
+    pub strict: bool,
+    /// Reject files whose header doesn't have exactly the expected columns
+    pub strict_headers: bool,
+    /// Whether the first row is a header, otherwise columns are read in the
+    /// type,client,tx,amount,currency,timestamp order
+    pub has_headers: bool,
+    /// Clients whose withdrawals may bring their available funds below zero
+    pub allow_overdraft: HashSet<ClientId>,
+    /// A negative deposit is a withdrawal of the opposite amount, and vice versa
+    pub treat_negative_as_reversal: bool,
+    /// Accept deposits and withdrawals of a zero amount
+    pub allow_zero_amounts: bool,
+    /// Number of operations kept for claims, unlimited if `None`. The ids of the evicted
+    /// ones are still remembered, so they are never reused
+    pub register_capacity: Option<usize>,
+    /// Kinds of operations that can be disputed
+    pub disputable_kinds: HashSet<TransactionKind>,
+    /// Fee charged on every withdrawal
+    pub fee_policy: FeePolicy,
+    /// Client credited with the withdrawal fees
+    pub fee_collector: ClientId,
+    /// Whether a dispute may bring the available funds below zero
+    pub dispute_policy: DisputePolicy,
+    /// Lowest available funds a dispute may leave, unlimited if `None`
+    pub min_available: Option<Amount>,
+    /// Maximum number of deposits and withdrawals per client, unlimited if `None`
+    pub max_operations_per_client: Option<u32>,
+    /// Whether transaction ids are unique in the whole feed or only per client
+    pub transaction_id_scope: TransactionIdScope,
+    /// A resolve is accepted on a locked account and unlocks it
+    pub resolve_unlocks: bool,
+    /// Deposits are accepted on locked accounts, withdrawals and claims are still rejected
+    pub locked_allows_deposit: bool,
+    /// Kinds of transactions processed, the other ones are skipped without error
+    pub allowed_kinds: HashSet<TransactionKind>,
+    /// Lines of an unknown type are skipped with a warning instead of being rejected
+    pub skip_unknown_kinds: bool,
+    /// Rows read from a file before giving up on the rest, unlimited if `None`
+    pub max_rows: Option<usize>,
+    /// A resolve of a transaction that isn't disputed is accepted and does nothing
+    pub resolve_noop_if_not_disputed: bool,
+    /// The id of a charged back transaction can be used by a new deposit or withdrawal
+    pub reuse_charged_back_ids: bool,
+    /// A transaction whose dispute was resolved can be disputed again
+    pub redispute_allowed: bool,
+    /// Whether a rejected line only drops itself or the whole run
+    pub transaction_mode: TransactionMode,
+}
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum FeePolicy {
+    #[default]
+    NoFee,
+    Flat(Amount),
+    /// Percentage of the withdrawn amount
+    Percentage(Amount),
+}
+
+impl FeePolicy {
+    /// `None` if the fee overflows
+    pub fn fee(&self, amount: Amount) -> Option<Amount> {
+        match *self {
+            Self::NoFee => Some(Amount::ZERO),
+            Self::Flat(fee) => Some(fee),
+            Self::Percentage(rate) => amount.percentage(rate),
+        }
+    }
+}
+
+/// What to do with a dispute on funds that are no longer available
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, EnumString)]
+#[strum(serialize_all = "kebab-case")]
+pub enum DisputePolicy {
+    #[default]
+    AllowNegative,
+    RejectIfNegative,
+}
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, EnumString, Deserialize, Serialize)]
+#[strum(serialize_all = "kebab-case")]
+#[serde(rename_all = "kebab-case")]
+pub enum TransactionIdScope {
+    #[default]
+    Global,
+    /// Different clients may use the same transaction id
+    PerClient,
+}
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, EnumString)]
+#[strum(serialize_all = "kebab-case")]
+pub enum TransactionMode {
+    /// Rejected lines are dropped, the other ones are applied
+    #[default]
+    PerLine,
+    /// The run is only applied if no line is rejected
+    AllOrNothing,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            max_disputes: None,
+            max_client_id: None,
+            delimiter: b',',
+            decimal_separator: '.',
+            strict: false,
+            strict_headers: false,
+            has_headers: true,
+            allow_overdraft: HashSet::new(),
+            treat_negative_as_reversal: false,
+            allow_zero_amounts: false,
+            register_capacity: None,
+            disputable_kinds: [TransactionKind::Deposit, TransactionKind::Withdrawal]
+                .iter()
+                .copied()
+                .collect(),
+            fee_policy: FeePolicy::default(),
+            fee_collector: ClientId(0),
+            dispute_policy: DisputePolicy::default(),
+            min_available: None,
+            max_operations_per_client: None,
+            transaction_id_scope: TransactionIdScope::default(),
+            resolve_unlocks: false,
+            locked_allows_deposit: false,
+            allowed_kinds: TransactionKind::iter().collect(),
+            skip_unknown_kinds: false,
+            max_rows: None,
+            resolve_noop_if_not_disputed: false,
+            reuse_charged_back_ids: false,
+            redispute_allowed: true,
+            transaction_mode: TransactionMode::default(),
+        }
+    }
+}
@@ -1,14 +1,46 @@
 use {
-    serde::Deserialize,
-    std::{convert::TryFrom, io::Read},
-    strum_macros::{Display, EnumString},
+    log::{debug, error, warn},
+    serde::{Deserialize, Serialize},
+    std::{
+        collections::HashMap,
+        convert::TryFrom,
+        io::{Read, Write},
+    },
+    strum_macros::{Display, EnumIter, EnumString, IntoStaticStr},
 };
 
+pub mod amount;
+pub mod audit;
+pub mod binary;
 pub mod clients;
+pub mod config;
+pub mod metrics;
+pub mod parallel;
+pub mod report;
+pub mod state;
+#[cfg(feature = "tokio")]
+pub mod stream;
 pub mod transactions;
 use {clients::*, transactions::*};
 
-pub use {clients::ClientAccounts, transactions::MoneyOperationsRegister};
+#[cfg(feature = "tokio")]
+pub use stream::process_stream;
+pub use {
+    amount::{Amount, ParseAmountError, RoundingMode},
+    audit::{replay_audit, AuditEntry, AuditLog},
+    binary::read_transactions_binary,
+    clients::{
+        diff_summaries, AnomalyKind, BalanceDelta, ClientAccounts, Currency, DeltaKind,
+        DeltaOverflow, DuplicatedColumn, InvariantViolation, PlatformTotals, SummaryColumn,
+        TooManyDecimals, TotalsOverflow,
+    },
+    config::{Config, DisputePolicy, FeePolicy, TransactionIdScope, TransactionMode},
+    metrics::{Metrics, NoMetrics},
+    parallel::process_parallel,
+    report::ProcessingReport,
+    state::{load_state, save_state},
+    transactions::MoneyOperationsRegister,
+};
 
 #[derive(Debug, Deserialize)]
 pub struct TransactionLine {
@@ -18,34 +50,217 @@ pub struct TransactionLine {
     client_id: ClientId,
     #[serde(rename = "tx")]
     transaction_id: TransactionId,
-    amount: Option<f64>,
+    /// Parsed when the line is converted, see `Amount`'s `FromStr`
+    amount: Option<String>,
+    /// Optional column, claims use the currency of their transaction
+    #[serde(default)]
+    currency: Option<Currency>,
+    /// Optional RFC 3339 timestamp of a deposit or withdrawal
+    #[serde(default)]
+    timestamp: Option<String>,
+    /// Values of the unknown columns, by column name
+    #[serde(skip)]
+    metadata: HashMap<String, String>,
 }
 
-#[derive(Debug, Deserialize, Display, EnumString)]
+#[derive(
+    Copy, Clone, Debug, Eq, PartialEq, Hash, Deserialize, Serialize, Display, EnumIter, EnumString,
+)]
 #[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
 pub enum TransactionKind {
     Deposit,
     Withdrawal,
     Resolve,
     Dispute,
     Chargeback,
+    Unlock,
+    Close,
+    Accrue,
+    Authorize,
+    Capture,
+    Void,
+}
+
+impl TransactionLine {
+    /// Line of a transaction in the implicit currency, without timestamp
+    ///
+    /// ```
+    /// use payment_engine::{
+    ///     clients::ClientId,
+    ///     transactions::{TransactionId, TransactionOrder},
+    ///     Amount, TransactionKind, TransactionLine,
+    /// };
+    /// use std::convert::TryFrom;
+    ///
+    /// let line = TransactionLine::new(
+    ///     TransactionKind::Deposit,
+    ///     ClientId(1),
+    ///     TransactionId(7),
+    ///     Some(Amount::from("2.5")),
+    /// );
+    /// assert_eq!(Some("2.5"), line.amount());
+    /// let order = TransactionOrder::try_from(line).unwrap();
+    /// assert_eq!("deposit client=1 tx=7 amount=2.5", order.to_string());
+    /// ```
+    pub fn new(
+        transaction_type: TransactionKind,
+        client_id: ClientId,
+        transaction_id: TransactionId,
+        amount: Option<Amount>,
+    ) -> Self {
+        Self {
+            transaction_type,
+            client_id,
+            transaction_id,
+            amount: amount.map(|amount| amount.to_string()),
+            currency: None,
+            timestamp: None,
+            metadata: HashMap::new(),
+        }
+    }
+    pub fn transaction_type(&self) -> TransactionKind {
+        self.transaction_type
+    }
+    pub fn client_id(&self) -> ClientId {
+        self.client_id
+    }
+    pub fn transaction_id(&self) -> TransactionId {
+        self.transaction_id
+    }
+    /// Amount as written in the input, it is only parsed by the conversion
+    pub fn amount(&self) -> Option<&str> {
+        self.amount.as_deref()
+    }
+    pub fn currency(&self) -> Option<&Currency> {
+        self.currency.as_ref()
+    }
+    pub fn timestamp(&self) -> Option<&str> {
+        self.timestamp.as_deref()
+    }
+    pub fn metadata(&self) -> &HashMap<String, String> {
+        &self.metadata
+    }
+    /// Amount written with `separator` instead of a dot
+    fn with_decimal_separator(self, separator: char) -> Self {
+        Self {
+            amount: self.amount.map(|amount| amount.replace(separator, ".")),
+            ..self
+        }
+    }
+    /// Turns a negative deposit into a withdrawal of the opposite amount, and vice versa
+    fn reversed_if_negative(self) -> Self {
+        let magnitude = match self
+            .amount
+            .as_deref()
+            .map(|amount| amount.strip_prefix('-'))
+        {
+            Some(Some(magnitude)) => magnitude.to_string(),
+            _ => return self,
+        };
+        let transaction_type = match self.transaction_type {
+            TransactionKind::Deposit => TransactionKind::Withdrawal,
+            TransactionKind::Withdrawal => TransactionKind::Deposit,
+            _ => return self,
+        };
+        Self {
+            transaction_type,
+            amount: Some(magnitude),
+            ..self
+        }
+    }
 }
 
 type Result<T> = std::result::Result<T, Error>;
 
-#[derive(Debug)]
+#[derive(Debug, IntoStaticStr)]
 pub enum Error {
+    BadHeader {
+        unexpected: Vec<String>,
+        missing: Vec<String>,
+        duplicated: Vec<String>,
+    },
+    /// Line with only whitespace, not counted as a rejection
+    BlankLine,
+    ClientIdOutOfRange(ClientId),
     DeserializationError(csv::Error),
+    /// The rejected lines couldn't be written to the error output
+    ErrorOutput(std::io::Error),
+    InvalidAmount(ParseAmountError),
+    /// Kind of a binary record that isn't a known transaction kind
+    InvalidKindCode(u8),
+    /// Non-numeric client or transaction id
+    InvalidId {
+        column: String,
+        value: String,
+    },
+    InvalidTimestamp(chrono::ParseError),
+    MissingAmount,
+    NonFiniteAmount,
+    /// The fee collector has transactions of its own, which can't be processed in parallel
+    ParallelFeeCollector(ClientId),
+    /// The file has more rows than allowed, the remaining ones are ignored
+    RowLimitExceeded(usize),
+    /// Row that couldn't be deserialized, with its fields joined by commas
+    RowError {
+        row: String,
+        source: csv::Error,
+    },
+    /// Kind of transaction that isn't allowed by the config, not counted as a rejection
+    Skipped(TransactionKind),
+    /// Type column that isn't a known kind, skipped if the config says so
+    UnknownKind(String),
     TransactionError(transactions::TransactionError),
+    UnsupportedTransactionKind(TransactionKind),
     WrongArgument,
 }
 
+impl Error {
+    /// Name of the error variant, used to group errors in reports
+    pub fn category(&self) -> &'static str {
+        match self {
+            Error::TransactionError(te) => te.into(),
+            _ => self.into(),
+        }
+    }
+    /// Skipped and blank lines are counted apart and aren't reported as rejected
+    pub fn is_skip(&self) -> bool {
+        matches!(
+            self,
+            Error::BlankLine | Error::Skipped(_) | Error::UnknownKind(_)
+        )
+    }
+    /// The line couldn't be read, as opposed to a transaction that was refused
+    pub fn is_parse_error(&self) -> bool {
+        matches!(
+            self,
+            Error::BadHeader { .. }
+                | Error::DeserializationError(_)
+                | Error::InvalidAmount(_)
+                | Error::InvalidId { .. }
+                | Error::InvalidKindCode(_)
+                | Error::InvalidTimestamp(_)
+                | Error::NonFiniteAmount
+                | Error::RowError { .. }
+        )
+    }
+}
+
 impl From<csv::Error> for Error {
     fn from(err: csv::Error) -> Self {
         Self::DeserializationError(err)
     }
 }
 
+impl From<ParseAmountError> for Error {
+    fn from(err: ParseAmountError) -> Self {
+        match err {
+            ParseAmountError::NonFinite => Self::NonFiniteAmount,
+            _ => Self::InvalidAmount(err),
+        }
+    }
+}
+
 impl From<TransactionError> for Error {
     fn from(err: TransactionError) -> Self {
         Self::TransactionError(err)
@@ -59,83 +274,526 @@ impl std::fmt::Display for Error {
             "{}",
             match self {
                 Error::WrongArgument => "Wrong argument".to_string(),
+                Error::BadHeader {
+                    unexpected,
+                    missing,
+                    duplicated,
+                } => format!(
+                    "Bad header, unexpected columns: [{}], missing columns: [{}], duplicated columns: [{}]",
+                    unexpected.join(", "),
+                    missing.join(", "),
+                    duplicated.join(", ")
+                ),
+                Error::BlankLine => "Blank line".to_string(),
+                Error::ClientIdOutOfRange(client_id) =>
+                    format!("Client id {} is out of range", client_id),
                 Error::DeserializationError(de) => format!("{}", de),
+                Error::ErrorOutput(e) => format!("Failed to write the error output: {}", e),
+                Error::InvalidAmount(ae) => format!("{}", ae),
+                Error::InvalidKindCode(code) => format!("Invalid transaction kind code {}", code),
+                Error::InvalidId { column, value } =>
+                    format!("Invalid id in column {}: {}", column, value),
+                Error::InvalidTimestamp(te) => format!("Invalid timestamp: {}", te),
+                Error::MissingAmount => "Missing amount".to_string(),
+                Error::NonFiniteAmount => "Amount is not a finite number".to_string(),
+                Error::ParallelFeeCollector(client_id) => format!(
+                    "Fee collector {} has transactions, they can't be processed in parallel",
+                    client_id
+                ),
+                Error::RowError { row, source } => format!("{} (row: {})", source, row),
+                Error::RowLimitExceeded(max) =>
+                    format!("More than {} rows, the remaining ones are ignored", max),
+                Error::Skipped(kind) => format!("Skipped {} transaction", kind),
+                Error::UnknownKind(kind) => format!("Skipped unknown transaction type {}", kind),
                 Error::TransactionError(te) => format!("{}", te),
+                Error::UnsupportedTransactionKind(kind) =>
+                    format!("Unsupported transaction kind {}", kind),
             }
         )
     }
 }
 
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::DeserializationError(de) => Some(de),
+            Error::ErrorOutput(e) => Some(e),
+            Error::InvalidAmount(ae) => Some(ae),
+            Error::InvalidTimestamp(te) => Some(te),
+            Error::RowError { source, .. } => Some(source),
+            Error::TransactionError(te) => Some(te),
+            _ => None,
+        }
+    }
+}
+
+/// Checks the amount column against the kind of the transaction: deposits,
+/// withdrawals, authorizations and accruals need a non-negative amount, a dispute
+/// may carry one and the other claims can't.
+pub fn parse_amount(raw: Option<&str>, kind: TransactionKind) -> Result<Option<Amount>> {
+    let amount = raw.map(str::parse::<Amount>).transpose()?;
+    match (kind, amount) {
+        (TransactionKind::Deposit, None)
+        | (TransactionKind::Withdrawal, None)
+        | (TransactionKind::Accrue, None)
+        | (TransactionKind::Authorize, None) => Err(Error::MissingAmount),
+        (TransactionKind::Resolve, Some(_))
+        | (TransactionKind::Chargeback, Some(_))
+        | (TransactionKind::Unlock, Some(_))
+        | (TransactionKind::Close, Some(_))
+        | (TransactionKind::Capture, Some(_))
+        | (TransactionKind::Void, Some(_)) => Err(Error::WrongArgument),
+        (_, Some(amount)) if amount < Amount::ZERO => Err(Error::WrongArgument),
+        (_, amount) => Ok(amount),
+    }
+}
+
 impl std::convert::TryFrom<TransactionLine> for TransactionOrder {
     type Error = Error;
 
     fn try_from(line: TransactionLine) -> Result<TransactionOrder> {
-        Ok(match line.transaction_type {
-            TransactionKind::Deposit | TransactionKind::Withdrawal => {
+        let amount = parse_amount(line.amount.as_deref(), line.transaction_type)?;
+        Ok(match (line.transaction_type, amount) {
+            (TransactionKind::Deposit, Some(amount))
+            | (TransactionKind::Withdrawal, Some(amount))
+            | (TransactionKind::Authorize, Some(amount)) => {
                 TransactionOrder::MoneyOperation(MoneyOperation {
                     client_id: line.client_id,
                     transaction_id: line.transaction_id,
                     disputed: false,
-                    operation_kind: match (line.transaction_type, line.amount) {
-                        (TransactionKind::Deposit, Some(amount)) if amount >= 0. => {
-                            OperationKind::Deposit(amount)
-                        }
-                        (TransactionKind::Withdrawal, Some(amount)) if amount >= 0. => {
-                            OperationKind::Withdrawal(amount)
-                        }
-                        _ => return Err(Error::WrongArgument),
+                    dispute_count: 0,
+                    held_for_dispute: Amount::ZERO,
+                    resolved_once: false,
+                    currency: line.currency.unwrap_or_default(),
+                    timestamp: line
+                        .timestamp
+                        .as_deref()
+                        .map(chrono::DateTime::parse_from_rfc3339)
+                        .transpose()
+                        .map_err(Error::InvalidTimestamp)?
+                        .map(|timestamp| timestamp.with_timezone(&chrono::Utc)),
+                    operation_kind: match line.transaction_type {
+                        TransactionKind::Deposit => OperationKind::Deposit(amount),
+                        TransactionKind::Authorize => OperationKind::Authorization(amount),
+                        _ => OperationKind::Withdrawal(amount),
                     },
                 })
             }
-            _ => TransactionOrder::ClientClaim(ClientClaim {
+            (kind, amount) => TransactionOrder::ClientClaim(ClientClaim {
                 transaction_id: line.transaction_id,
                 client_id: line.client_id,
-                claim_kind: match line.transaction_type {
+                claim_kind: match kind {
                     TransactionKind::Resolve => ClientClaimKind::Resolve,
                     TransactionKind::Dispute => ClientClaimKind::Dispute,
                     TransactionKind::Chargeback => ClientClaimKind::Chargeback,
-                    _ => panic!("This can't happen"),
+                    TransactionKind::Unlock => ClientClaimKind::Unlock,
+                    TransactionKind::Close => ClientClaimKind::Close,
+                    TransactionKind::Accrue => ClientClaimKind::Accrue,
+                    TransactionKind::Capture => ClientClaimKind::Capture,
+                    TransactionKind::Void => ClientClaimKind::Void,
+                    kind => return Err(Error::UnsupportedTransactionKind(kind)),
                 },
+                amount,
             }),
         })
     }
 }
 
-pub fn read_transactions_file<R: Read>(
-    file: R,
+/// Processes a single transaction line, returning the error if it is rejected.
+pub fn process_line(
+    mut line: TransactionLine,
     accounts: &mut ClientAccounts,
     operations_register: &mut MoneyOperationsRegister,
-    debug_mode: bool,
-) {
-    for result in csv::ReaderBuilder::new()
+    config: &Config,
+    audit_log: Option<&mut AuditLog>,
+) -> Result<()> {
+    if !config.allowed_kinds.contains(&line.transaction_type) {
+        return Err(Error::Skipped(line.transaction_type));
+    }
+    let (client_id, metadata) = (line.client_id, std::mem::take(&mut line.metadata));
+    if matches!(config.max_client_id, Some(max) if line.client_id.0 > max) {
+        return Err(Error::ClientIdOutOfRange(line.client_id));
+    }
+    let line = if config.decimal_separator != '.' {
+        line.with_decimal_separator(config.decimal_separator)
+    } else {
+        line
+    };
+    let line = if config.treat_negative_as_reversal {
+        line.reversed_if_negative()
+    } else {
+        line
+    };
+    let order = TransactionOrder::try_from(line)?;
+    debug!("{}", order);
+    let currency = order.currency(operations_register);
+    order.process(accounts, operations_register, config, audit_log)?;
+    accounts.attach_metadata(client_id, &currency, metadata);
+    Ok(())
+}
+
+/// Transcodes the input to UTF-8, e.g. from `encoding_rs::WINDOWS_1252` which also
+/// covers latin-1. A byte order mark takes precedence over `encoding`.
+pub fn decoding_reader<R: Read>(file: R, encoding: &'static encoding_rs::Encoding) -> impl Read {
+    encoding_rs_io::DecodeReaderBytesBuilder::new()
+        .encoding(Some(encoding))
+        .build(file)
+}
+
+pub(crate) fn csv_reader<R: Read>(file: R, config: &Config) -> csv::Reader<R> {
+    csv::ReaderBuilder::new()
+        .delimiter(config.delimiter)
+        .has_headers(config.has_headers)
         .flexible(true)
         .trim(csv::Trim::All)
         .from_reader(file)
-        .deserialize::<TransactionLine>()
-        .map(|line| {
-            TransactionOrder::try_from(line?)?
-                .process(accounts, operations_register)
-                .map_err(|e| Error::from(e))
+}
+
+const COLUMNS: [&str; 4] = ["type", "client", "tx", "amount"];
+const OPTIONAL_COLUMNS: [&str; 2] = ["currency", "timestamp"];
+
+/// Compares the header with the known columns, in any order
+fn check_headers(headers: &csv::StringRecord) -> Option<Error> {
+    let unexpected: Vec<String> = headers
+        .iter()
+        .filter(|column| !COLUMNS.contains(column) && !OPTIONAL_COLUMNS.contains(column))
+        .map(String::from)
+        .collect();
+    let missing: Vec<String> = COLUMNS
+        .iter()
+        .filter(|column| !headers.iter().any(|header| header == **column))
+        .map(|column| column.to_string())
+        .collect();
+    let duplicated: Vec<String> = headers
+        .iter()
+        .enumerate()
+        .filter(|(i, column)| headers.iter().take(*i).any(|previous| previous == *column))
+        .map(|(_, column)| column.to_string())
+        .collect();
+    if unexpected.is_empty() && missing.is_empty() && duplicated.is_empty() {
+        return None;
+    }
+    Some(Error::BadHeader {
+        unexpected,
+        missing,
+        duplicated,
+    })
+}
+
+/// Deserialized lines of the file, along with their 1-based line number.
+/// With strict headers, a bad header is the only error returned.
+pub(crate) fn numbered_lines<R: Read>(
+    file: R,
+    config: &Config,
+) -> impl Iterator<Item = (usize, Result<TransactionLine>)> {
+    let mut reader = csv_reader(file, config);
+    let headers = if config.has_headers {
+        reader.headers().ok().cloned()
+    } else {
+        None
+    };
+    let header_error = headers
+        .as_ref()
+        .filter(|_| config.strict_headers)
+        .and_then(check_headers);
+    let bad_header = header_error.is_some();
+    let max_rows = config.max_rows;
+    let skip_unknown_kinds = config.skip_unknown_kinds;
+    let header_error = header_error.map(|e| (1, Err(e)));
+    header_error.into_iter().chain(
+        reader
+            .into_records()
+            .take_while(move |_| !bad_header)
+            .map(move |record| {
+                let line = match &record {
+                    Ok(record) => record.position(),
+                    Err(e) => e.position(),
+                }
+                .map_or(0, |position| position.line() as usize);
+                (
+                    line,
+                    record.map_err(Error::from).and_then(|record| {
+                        deserialize_line(&record, headers.as_ref(), skip_unknown_kinds)
+                    }),
+                )
+            })
+            // The first row over the limit is reported, the next ones aren't read
+            .scan(0, move |rows, (line, result)| {
+                *rows += 1;
+                match max_rows {
+                    Some(max) if *rows > max + 1 => None,
+                    Some(max) if *rows == max + 1 => {
+                        Some((line, Err(Error::RowLimitExceeded(max))))
+                    }
+                    _ => Some((line, result)),
+                }
+            }),
+    )
+}
+
+/// Names the column and the value of a rejected id, other errors carry the row.
+/// An unknown type is only skipped when `skip_unknown_kinds` is set.
+fn deserialize_line(
+    record: &csv::StringRecord,
+    headers: Option<&csv::StringRecord>,
+    skip_unknown_kinds: bool,
+) -> Result<TransactionLine> {
+    // Empty lines are already dropped by the reader, whitespace is trimmed
+    if record.iter().all(str::is_empty) {
+        return Err(Error::BlankLine);
+    }
+    let mut line: TransactionLine = record.deserialize(headers).map_err(|e| {
+        let field = match e.kind() {
+            csv::ErrorKind::Deserialize { err, .. } => err.field(),
+            _ => None,
+        };
+        let column_at = |field: usize| match headers {
+            Some(headers) => headers.get(field),
+            None => COLUMNS
+                .iter()
+                .chain(OPTIONAL_COLUMNS.iter())
+                .nth(field)
+                .copied(),
+        };
+        // Errors raised by the visitors, e.g. of the ids or the type, don't carry
+        // their field: the first of these columns that doesn't parse is used instead
+        let column = field
+            .and_then(|field| Some((column_at(field as usize)?, field)))
+            .or_else(|| {
+                (0..record.len()).find_map(|field| {
+                    let (column, value) = (column_at(field)?, record.get(field)?);
+                    let invalid = match column {
+                        "type" => value.parse::<TransactionKind>().is_err(),
+                        "client" | "tx" => value.parse::<u32>().is_err(),
+                        _ => false,
+                    };
+                    invalid.then_some((column, field as u64))
+                })
+            });
+        match column {
+            Some((column, field)) if column == "client" || column == "tx" => Error::InvalidId {
+                column: column.to_string(),
+                value: record.get(field as usize).unwrap_or_default().to_string(),
+            },
+            Some((column, field))
+                if column == "type"
+                    && skip_unknown_kinds
+                    && record
+                        .get(field as usize)
+                        .is_some_and(|kind| kind.parse::<TransactionKind>().is_err()) =>
+            {
+                Error::UnknownKind(record.get(field as usize).unwrap_or_default().to_string())
+            }
+            _ => Error::RowError {
+                row: record.iter().collect::<Vec<_>>().join(","),
+                source: e,
+            },
+        }
+    })?;
+    if let Some(headers) = headers {
+        line.metadata = headers
+            .iter()
+            .zip(record.iter())
+            .filter(|(column, _)| !COLUMNS.contains(column) && !OPTIONAL_COLUMNS.contains(column))
+            .map(|(column, value)| (column.to_string(), value.to_string()))
+            .collect();
+    }
+    Ok(line)
+}
+
+/// Parses a single row without header, in the type,client,tx,amount,currency,timestamp
+/// order, trimmed and with optional trailing columns as in the files.
+pub fn parse_line(record: &str) -> Result<TransactionLine> {
+    let config = Config {
+        has_headers: false,
+        ..Config::default()
+    };
+    let record = csv_reader(record.as_bytes(), &config)
+        .records()
+        .next()
+        .transpose()?
+        .unwrap_or_default();
+    deserialize_line(&record, None, false)
+}
+
+/// Processes the file against a throwaway state, returning every rejected line
+/// with its line number.
+pub fn validate_file<R: Read>(file: R, config: &Config) -> Vec<(usize, Error)> {
+    let mut accounts = ClientAccounts::new();
+    let mut operations_register = MoneyOperationsRegister::from_config(config);
+    numbered_lines(file, config)
+        .filter_map(|(line_number, line)| {
+            line.and_then(|line| {
+                process_line(line, &mut accounts, &mut operations_register, config, None)
+            })
+            .err()
+            .filter(|e| !e.is_skip())
+            .map(|e| (line_number, e))
         })
+        .collect()
+}
+
+/// Outcome of each line, processed only when the caller pulls it
+pub fn transaction_results<'a, R: Read + 'a>(
+    file: R,
+    accounts: &'a mut ClientAccounts,
+    operations_register: &'a mut MoneyOperationsRegister,
+    config: &'a Config,
+) -> impl Iterator<Item = Result<()>> + 'a {
+    processed_lines(file, accounts, operations_register, config, None)
+        .map(|(_, result)| result.map(|_| ()))
+}
+
+/// Processes the file against a copy of the accounts and operations, which replaces
+/// them only if no line is rejected. Otherwise they are left untouched and the first
+/// rejected line is returned with its line number. Skipped lines don't count as rejected.
+pub fn process_all_or_nothing<R: Read>(
+    file: R,
+    accounts: &mut ClientAccounts,
+    operations_register: &mut MoneyOperationsRegister,
+    config: &Config,
+) -> std::result::Result<ProcessingReport, (usize, Error)> {
+    let mut staged_accounts = accounts.clone();
+    let mut staged_register = operations_register.clone();
+    let mut report = ProcessingReport::default();
+    for (line_number, result) in processed_lines(
+        file,
+        &mut staged_accounts,
+        &mut staged_register,
+        config,
+        None,
+    ) {
+        match result.map(|_| ()) {
+            Err(e) if !e.is_skip() => return Err((line_number, e)),
+            result => report.record(&result),
+        }
+    }
+    *accounts = staged_accounts;
+    *operations_register = staged_register;
+    Ok(report)
+}
+
+/// Line number and outcome of each line, with the kind of the processed transactions
+fn processed_lines<'a, R: Read + 'a>(
+    file: R,
+    accounts: &'a mut ClientAccounts,
+    operations_register: &'a mut MoneyOperationsRegister,
+    config: &'a Config,
+    mut audit_log: Option<&'a mut AuditLog>,
+) -> impl Iterator<Item = (usize, Result<TransactionKind>)> + 'a {
+    numbered_lines(file, config).map(move |(line_number, line)| {
+        let result = line.and_then(|line| {
+            let kind = line.transaction_type;
+            process_line(
+                line,
+                accounts,
+                operations_register,
+                config,
+                audit_log.as_deref_mut(),
+            )
+            .map(|()| kind)
+        });
+        (line_number, result)
+    })
+}
+
+/// Rejected lines are reported to `error_output`, prefixed with their line number.
+/// With `strict` set in the config, the first line that can't be read stops the
+/// processing and its error is returned.
+pub fn read_transactions_file<R: Read>(
+    file: R,
+    accounts: &mut ClientAccounts,
+    operations_register: &mut MoneyOperationsRegister,
+    config: &Config,
+    audit_log: Option<&mut AuditLog>,
+    mut error_output: Option<&mut dyn Write>,
+    metrics: &dyn Metrics,
+) -> Result<ProcessingReport> {
+    let mut report = ProcessingReport::default();
+    for (line_number, result) in
+        processed_lines(file, accounts, operations_register, config, audit_log)
     {
-        match (debug_mode, result) {
-            (true, Err(e)) => println!("{}", e),
-            _ => {}
+        let result = result.map(|kind| metrics.transaction_processed(kind));
+        report.record(&result);
+        match result {
+            Ok(()) => {}
+            Err(e) => {
+                log_error(line_number, &e);
+                if e.is_skip() {
+                    continue;
+                }
+                metrics.transaction_rejected(e.category());
+                if let Some(output) = error_output.as_deref_mut() {
+                    write_error(output, line_number, &e).map_err(Error::ErrorOutput)?;
+                }
+                if config.strict && e.is_parse_error() {
+                    return Err(e);
+                }
+            }
+        }
+    }
+    metrics.active_clients(accounts.inner.len());
+    Ok(report)
+}
+
+/// Processes lines that were already parsed, errors are logged with the position
+/// of the line in the batch, starting at 1.
+pub fn process_batch(
+    lines: Vec<TransactionLine>,
+    accounts: &mut ClientAccounts,
+    operations_register: &mut MoneyOperationsRegister,
+    config: &Config,
+) -> ProcessingReport {
+    let mut report = ProcessingReport::default();
+    for (i, line) in lines.into_iter().enumerate() {
+        let result = process_line(line, accounts, operations_register, config, None);
+        report.record(&result);
+        if let Err(e) = &result {
+            log_error(i + 1, e);
         }
     }
+    report
+}
+
+/// Rejected transactions are logged as warnings, unreadable lines as errors
+pub(crate) fn log_error(line_number: usize, e: &Error) {
+    match e {
+        Error::TransactionError(_) | Error::ClientIdOutOfRange(_) => {
+            warn!("line {}: {}", line_number, e)
+        }
+        Error::BlankLine | Error::Skipped(_) => debug!("line {}: {}", line_number, e),
+        Error::UnknownKind(_) => warn!("line {}: {}", line_number, e),
+        _ => error!("line {}: {}", line_number, e),
+    }
+}
+
+pub(crate) fn write_error(
+    output: &mut dyn Write,
+    line_number: usize,
+    e: &Error,
+) -> std::io::Result<()> {
+    writeln!(output, "line {}: {}", line_number, e)
 }
 
 #[cfg(test)]
 mod tests {
     fn try_input(input: &str) -> Vec<u8> {
+        try_input_with(input, &crate::Config::default())
+    }
+
+    fn try_input_with(input: &str, config: &crate::Config) -> Vec<u8> {
         let mut accounts = crate::ClientAccounts::new();
-        let mut operations_register = crate::MoneyOperationsRegister::new();
+        let mut operations_register = crate::MoneyOperationsRegister::from_config(config);
         crate::read_transactions_file(
             input.as_bytes(),
             &mut accounts,
             &mut operations_register,
-            false,
-        );
+            config,
+            None,
+            None,
+            &crate::NoMetrics,
+        )
+        .unwrap();
         let mut buf = Vec::new();
         accounts.print_to(&mut buf).unwrap();
         buf
@@ -145,17 +803,22 @@ mod tests {
     fn wrong_format() {
         let sample_operation = "type, 		client,	tx,	amount
         Deposit,	1.0,	1,	2.0";
-        let output = try_input(&sample_operation);
-        assert_eq!("", std::str::from_utf8(&output).unwrap());
+        let output = try_input(sample_operation);
+        assert_eq!(
+            "client,available,held,total,locked\n",
+            std::str::from_utf8(&output).unwrap()
+        );
     }
 
     #[test]
     fn precision() {
         let sample_operation = "type, 		client,	tx,	amount
-        deposit,	1,	1,	2.234235";
-        let output = try_input(&sample_operation);
+        deposit,	1,	1,	2.2342
+        deposit,	1,	2,	0.0001
+        deposit,	2,	3,	2.234235";
+        let output = try_input(sample_operation);
         assert_eq!(
-            "client,available,held,total,locked\n1,2.2342,0.0,2.2342,false\n",
+            "client,available,held,total,locked\n1,2.2343,0.0,2.2343,false\n",
             std::str::from_utf8(&output).unwrap()
         );
     }
@@ -165,7 +828,7 @@ mod tests {
         let sample_operation = "type, 		client,	tx,	amount
         deposit,	1,	1,	2.0
         withdrawal, 2, 2, 1.0";
-        let output = try_input(&sample_operation);
+        let output = try_input(sample_operation);
         assert_eq!(
             "client,available,held,total,locked\n1,2.0,0.0,2.0,false\n",
             std::str::from_utf8(&output).unwrap()
@@ -177,7 +840,7 @@ mod tests {
         let sample_operation = "type, 		client,	tx,	amount
         deposit,	1,	1,	2.0
         withdrawal, 2, 2, 5.0";
-        let output = try_input(&sample_operation);
+        let output = try_input(sample_operation);
         assert_eq!(
             "client,available,held,total,locked\n1,2.0,0.0,2.0,false\n",
             std::str::from_utf8(&output).unwrap()
@@ -189,10 +852,3263 @@ mod tests {
         let sample_operation = "type, 		client,	tx,	amount
         deposit,	1,	1,	2.0
         resolve, 1, 1,";
-        let output = try_input(&sample_operation);
+        let output = try_input(sample_operation);
+        assert_eq!(
+            "client,available,held,total,locked\n1,2.0,0.0,2.0,false\n",
+            std::str::from_utf8(&output).unwrap()
+        );
+    }
+
+    #[test]
+    fn deposit_overflow() {
+        let sample_operation = "type, 		client,	tx,	amount
+        deposit,	1,	1,	900000000000000
+        deposit,	1,	2,	900000000000000";
+        let output = try_input(sample_operation);
+        assert_eq!(
+            "client,available,held,total,locked\n1,900000000000000.0,0.0,900000000000000.0,false\n",
+            std::str::from_utf8(&output).unwrap()
+        );
+    }
+
+    #[test]
+    fn deposit_dispute_resolve() {
+        let sample_operation = "type, 		client,	tx,	amount
+        deposit,	1,	1,	10.0
+        withdrawal,	1,	2,	3.0
+        dispute,	1,	1,
+        deposit,	1,	3,	0.5";
+        let output = try_input(sample_operation);
+        assert_eq!(
+            "client,available,held,total,locked\n1,-2.5,10.0,7.5,false\n",
+            std::str::from_utf8(&output).unwrap()
+        );
+
+        let sample_operation = "type, 		client,	tx,	amount
+        deposit,	1,	1,	10.0
+        withdrawal,	1,	2,	3.0
+        dispute,	1,	1,
+        resolve,	1,	1,";
+        let output = try_input(sample_operation);
+        assert_eq!(
+            "client,available,held,total,locked\n1,7.0,0.0,7.0,false\n",
+            std::str::from_utf8(&output).unwrap()
+        );
+    }
+
+    #[test]
+    fn withdrawal_dispute_resolve() {
+        let sample_operation = "type, 		client,	tx,	amount
+        deposit,	1,	1,	10.0
+        withdrawal,	1,	2,	4.0
+        dispute,	1,	2,";
+        let output = try_input(sample_operation);
+        assert_eq!(
+            "client,available,held,total,locked\n1,6.0,4.0,10.0,false\n",
+            std::str::from_utf8(&output).unwrap()
+        );
+
+        let sample_operation = "type, 		client,	tx,	amount
+        deposit,	1,	1,	10.0
+        withdrawal,	1,	2,	4.0
+        dispute,	1,	2,
+        resolve,	1,	2,";
+        let output = try_input(sample_operation);
+        assert_eq!(
+            "client,available,held,total,locked\n1,6.0,0.0,6.0,false\n",
+            std::str::from_utf8(&output).unwrap()
+        );
+    }
+
+    #[test]
+    fn withdrawal_dispute_chargeback() {
+        let sample_operation = "type, 		client,	tx,	amount
+        deposit,	1,	1,	10.0
+        withdrawal,	1,	2,	4.0
+        dispute,	1,	2,
+        chargeback,	1,	2,";
+        let output = try_input(sample_operation);
+        assert_eq!(
+            "client,available,held,total,locked\n1,10.0,0.0,10.0,true\n",
+            std::str::from_utf8(&output).unwrap()
+        );
+    }
+
+    #[test]
+    fn chargeback_without_dispute() {
+        let mut accounts = crate::ClientAccounts::new();
+        let mut operations_register = crate::MoneyOperationsRegister::new();
+        accounts.create_client(crate::ClientId(1), crate::Amount::from("10"));
+        operations_register.insert(crate::MoneyOperation {
+            client_id: crate::ClientId(1),
+            transaction_id: crate::TransactionId(1),
+            disputed: false,
+            dispute_count: 0,
+            held_for_dispute: crate::Amount::ZERO,
+            resolved_once: false,
+            operation_kind: crate::OperationKind::Deposit(crate::Amount::from("10")),
+            currency: Default::default(),
+            timestamp: None,
+        });
+        let chargeback = crate::ClientClaim {
+            client_id: crate::ClientId(1),
+            transaction_id: crate::TransactionId(1),
+            claim_kind: crate::ClientClaimKind::Chargeback,
+            amount: None,
+        };
+        assert!(matches!(
+            chargeback.process(
+                &mut accounts,
+                &mut operations_register,
+                &crate::Config::default()
+            ),
+            Err(crate::TransactionError::WrongTransactionState)
+        ));
+    }
+
+    #[test]
+    fn deposit_dispute_chargeback() {
+        let sample_operation = "type, 		client,	tx,	amount
+        deposit,	1,	1,	10.0
+        deposit,	1,	2,	5.0
+        dispute,	1,	1,
+        chargeback,	1,	1,";
+        let output = try_input(sample_operation);
+        assert_eq!(
+            "client,available,held,total,locked\n1,5.0,0.0,5.0,true\n",
+            std::str::from_utf8(&output).unwrap()
+        );
+    }
+
+    #[test]
+    fn json_output() {
+        let mut accounts = crate::ClientAccounts::new();
+        accounts.create_client(crate::ClientId(1), crate::Amount::from("2.2342"));
+        let mut buf = Vec::new();
+        accounts.print_json_to(&mut buf).unwrap();
+        assert_eq!(
+            "{\"client\":1,\"available\":2.2342,\"held\":0.0,\"total\":2.2342,\"locked\":false}\n",
+            std::str::from_utf8(&buf).unwrap()
+        );
+    }
+
+    #[test]
+    fn sorted_output() {
+        let mut accounts = crate::ClientAccounts::new();
+        for id in [3, 1, 2] {
+            accounts.create_client(crate::ClientId(id), crate::Amount::from("1"));
+        }
+        let mut buf = Vec::new();
+        accounts.print_to(&mut buf).unwrap();
+        assert_eq!(
+            "client,available,held,total,locked\n1,1.0,0.0,1.0,false\n2,1.0,0.0,1.0,false\n3,1.0,0.0,1.0,false\n",
+            std::str::from_utf8(&buf).unwrap()
+        );
+    }
+
+    #[test]
+    fn process_line_error() {
+        let mut accounts = crate::ClientAccounts::new();
+        let mut operations_register = crate::MoneyOperationsRegister::new();
+        let line = crate::TransactionLine {
+            transaction_type: crate::TransactionKind::Withdrawal,
+            client_id: crate::ClientId(1),
+            transaction_id: crate::TransactionId(1),
+            amount: Some(String::from("1")),
+            currency: None,
+            timestamp: None,
+            metadata: Default::default(),
+        };
+        assert!(matches!(
+            crate::process_line(
+                line,
+                &mut accounts,
+                &mut operations_register,
+                &crate::Config::default(),
+                None
+            ),
+            Err(crate::Error::TransactionError(
+                crate::TransactionError::MissingClient(crate::ClientId(1))
+            ))
+        ));
+    }
+
+    #[test]
+    fn processing_report() {
+        let sample_operation = "type, 		client,	tx,	amount
+        deposit,	1,	1,	2.0
+        deposit,	x,	2,	2.0
+        withdrawal,	1,	3,	5.0";
+        let mut accounts = crate::ClientAccounts::new();
+        let mut operations_register = crate::MoneyOperationsRegister::new();
+        let report = crate::read_transactions_file(
+            sample_operation.as_bytes(),
+            &mut accounts,
+            &mut operations_register,
+            &crate::Config::default(),
+            None,
+            None,
+            &crate::NoMetrics,
+        )
+        .unwrap();
+        assert_eq!(3, report.lines);
+        assert_eq!(1, report.processed);
+        assert_eq!(Some(&1), report.errors.get("InvalidId"));
+        assert_eq!(Some(&1), report.errors.get("NotEnoughFunds"));
+        assert_eq!(2, report.errors.len());
+    }
+
+    #[test]
+    fn client_mismatch() {
+        let sample_operation = "type, 		client,	tx,	amount
+        deposit,	1,	5,	2.0
+        deposit,	2,	6,	3.0";
+        let mut accounts = crate::ClientAccounts::new();
+        let mut operations_register = crate::MoneyOperationsRegister::new();
+        crate::read_transactions_file(
+            sample_operation.as_bytes(),
+            &mut accounts,
+            &mut operations_register,
+            &crate::Config::default(),
+            None,
+            None,
+            &crate::NoMetrics,
+        )
+        .unwrap();
+        let dispute = crate::TransactionLine {
+            transaction_type: crate::TransactionKind::Dispute,
+            client_id: crate::ClientId(2),
+            transaction_id: crate::TransactionId(5),
+            amount: None,
+            currency: None,
+            timestamp: None,
+            metadata: Default::default(),
+        };
+        assert!(matches!(
+            crate::process_line(
+                dispute,
+                &mut accounts,
+                &mut operations_register,
+                &crate::Config::default(),
+                None
+            ),
+            Err(crate::Error::TransactionError(
+                crate::TransactionError::ClientMismatch {
+                    claim: crate::ClientId(2),
+                    operation: crate::ClientId(1),
+                }
+            ))
+        ));
+        let mut buf = Vec::new();
+        accounts.print_to(&mut buf).unwrap();
+        assert_eq!(
+            "client,available,held,total,locked\n1,2.0,0.0,2.0,false\n2,3.0,0.0,3.0,false\n",
+            std::str::from_utf8(&buf).unwrap()
+        );
+    }
+
+    #[test]
+    fn gzip_input() {
+        use std::io::Write;
+
+        let sample_operation = "type, 		client,	tx,	amount
+        deposit,	1,	1,	2.0
+        withdrawal,	1,	2,	0.5";
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(sample_operation.as_bytes()).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let mut accounts = crate::ClientAccounts::new();
+        let mut operations_register = crate::MoneyOperationsRegister::new();
+        crate::read_transactions_file(
+            flate2::read::GzDecoder::new(compressed.as_slice()),
+            &mut accounts,
+            &mut operations_register,
+            &crate::Config::default(),
+            None,
+            None,
+            &crate::NoMetrics,
+        )
+        .unwrap();
+        let mut buf = Vec::new();
+        accounts.print_to(&mut buf).unwrap();
+        assert_eq!(
+            "client,available,held,total,locked\n1,1.5,0.0,1.5,false\n",
+            std::str::from_utf8(&buf).unwrap()
+        );
+    }
+
+    #[test]
+    fn parallel_matches_serial() {
+        let sample_operation = "type, 		client,	tx,	amount
+        deposit,	1,	1,	10.0
+        deposit,	2,	2,	5.0
+        deposit,	3,	3,	7.5
+        withdrawal,	1,	4,	2.0
+        withdrawal,	2,	5,	6.0
+        deposit,	x,	6,	1.0
+        dispute,	1,	1,
+        deposit,	4,	7,	1.25
+        dispute,	3,	3,
+        chargeback,	3,	3,
+        deposit,	3,	8,	1.0
+        resolve,	1,	1,
+        withdrawal,	4,	9,	0.25
+        dispute,	2,	2,";
+        let serial = try_input(sample_operation);
+        let (accounts, report) = crate::process_parallel(
+            std::iter::once(sample_operation.as_bytes()),
+            3,
+            &crate::Config::default(),
+        )
+        .unwrap();
+        let mut parallel = Vec::new();
+        accounts.print_to(&mut parallel).unwrap();
+        assert_eq!(
+            std::str::from_utf8(&serial).unwrap(),
+            std::str::from_utf8(&parallel).unwrap()
+        );
+        assert_eq!(14, report.lines);
+        assert_eq!(3, report.rejected());
+    }
+
+    #[test]
+    fn dispute_limit() {
+        let sample_operation = "type, 		client,	tx,	amount
+        deposit,	1,	1,	2.0
+        dispute,	1,	1,
+        resolve,	1,	1,
+        dispute,	1,	1,";
+        let config = crate::Config {
+            max_disputes: Some(1),
+            ..Default::default()
+        };
+        let output = try_input_with(sample_operation, &config);
         assert_eq!(
             "client,available,held,total,locked\n1,2.0,0.0,2.0,false\n",
             std::str::from_utf8(&output).unwrap()
         );
+        let output = try_input(sample_operation);
+        assert_eq!(
+            "client,available,held,total,locked\n1,0.0,2.0,2.0,false\n",
+            std::str::from_utf8(&output).unwrap()
+        );
+    }
+
+    #[test]
+    fn audit_log() {
+        let sample_operation = "type, 		client,	tx,	amount
+        deposit,	1,	1,	2.0
+        dispute,	1,	1,";
+        let mut accounts = crate::ClientAccounts::new();
+        let mut operations_register = crate::MoneyOperationsRegister::new();
+        let mut audit_log = crate::AuditLog::new();
+        crate::read_transactions_file(
+            sample_operation.as_bytes(),
+            &mut accounts,
+            &mut operations_register,
+            &crate::Config::default(),
+            Some(&mut audit_log),
+            None,
+            &crate::NoMetrics,
+        )
+        .unwrap();
+        let mut buf = Vec::new();
+        audit_log.write_csv_to(&mut buf).unwrap();
+        assert_eq!(
+            "tx,client,kind,available_before,held_before,available_after,held_after,result\n\
+            1,1,deposit,0.0,0.0,2.0,0.0,ok\n\
+            1,1,dispute,2.0,0.0,0.0,2.0,ok\n",
+            std::str::from_utf8(&buf).unwrap()
+        );
+    }
+
+    #[test]
+    fn balance_of() {
+        let mut accounts = crate::ClientAccounts::new();
+        accounts.create_client(crate::ClientId(1), crate::Amount::from("1.5"));
+        accounts.create_client(crate::ClientId(2), crate::Amount::from("3"));
+        assert_eq!(
+            Some(crate::AccountSummary {
+                client: crate::ClientId(2),
+                currency: None,
+                available: crate::Amount::from("3"),
+                held: crate::Amount::ZERO,
+                total: crate::Amount::from("3"),
+                locked: false,
+                deposited: None,
+                withdrawn: None,
+            }),
+            accounts.balance_of(crate::ClientId(2))
+        );
+        assert_eq!(None, accounts.balance_of(crate::ClientId(3)));
+    }
+
+    #[test]
+    fn client_id_out_of_range() {
+        let sample_operation = "type, 		client,	tx,	amount
+        deposit,	1,	1,	2.0
+        deposit,	70000,	2,	2.0";
+        let config = crate::Config {
+            max_client_id: Some(u16::MAX.into()),
+            ..Default::default()
+        };
+        let output = try_input_with(sample_operation, &config);
+        assert_eq!(
+            "client,available,held,total,locked\n1,2.0,0.0,2.0,false\n",
+            std::str::from_utf8(&output).unwrap()
+        );
+    }
+
+    #[test]
+    fn overlapping_disputes() {
+        let sample_operation = "type, 		client,	tx,	amount
+        deposit,	1,	1,	10.0
+        deposit,	1,	2,	5.0
+        dispute,	1,	1,
+        dispute,	1,	2,
+        resolve,	1,	2,";
+        let output = try_input(sample_operation);
+        assert_eq!(
+            "client,available,held,total,locked\n1,5.0,10.0,15.0,false\n",
+            std::str::from_utf8(&output).unwrap()
+        );
+
+        let sample_operation = format!("{}\nresolve,	1,	1,", sample_operation);
+        let output = try_input(&sample_operation);
+        assert_eq!(
+            "client,available,held,total,locked\n1,15.0,0.0,15.0,false\n",
+            std::str::from_utf8(&output).unwrap()
+        );
+    }
+
+    #[test]
+    fn duplicate_claim() {
+        let sample_operation = "type, 		client,	tx,	amount
+        deposit,	1,	1,	2.0
+        dispute,	1,	1,";
+        let mut accounts = crate::ClientAccounts::new();
+        let mut operations_register = crate::MoneyOperationsRegister::new();
+        crate::read_transactions_file(
+            sample_operation.as_bytes(),
+            &mut accounts,
+            &mut operations_register,
+            &crate::Config::default(),
+            None,
+            None,
+            &crate::NoMetrics,
+        )
+        .unwrap();
+        let dispute = |client_id| crate::TransactionLine {
+            transaction_type: crate::TransactionKind::Dispute,
+            client_id,
+            transaction_id: crate::TransactionId(1),
+            amount: None,
+            currency: None,
+            timestamp: None,
+            metadata: Default::default(),
+        };
+        assert!(matches!(
+            crate::process_line(
+                dispute(crate::ClientId(1)),
+                &mut accounts,
+                &mut operations_register,
+                &crate::Config::default(),
+                None
+            ),
+            Err(crate::Error::TransactionError(
+                crate::TransactionError::DuplicateClaim(crate::TransactionId(1))
+            ))
+        ));
+        // The owner and the lock are checked before the replay
+        assert!(matches!(
+            crate::process_line(
+                dispute(crate::ClientId(2)),
+                &mut accounts,
+                &mut operations_register,
+                &crate::Config::default(),
+                None
+            ),
+            Err(crate::Error::TransactionError(
+                crate::TransactionError::ClientMismatch { .. }
+            ))
+        ));
+        crate::read_transactions_file(
+            "type,client,tx,amount\ndeposit,1,2,1.0\ndispute,1,2,\nchargeback,1,2,".as_bytes(),
+            &mut accounts,
+            &mut operations_register,
+            &crate::Config::default(),
+            None,
+            None,
+            &crate::NoMetrics,
+        )
+        .unwrap();
+        assert!(matches!(
+            crate::process_line(
+                dispute(crate::ClientId(1)),
+                &mut accounts,
+                &mut operations_register,
+                &crate::Config::default(),
+                None
+            ),
+            Err(crate::Error::TransactionError(
+                crate::TransactionError::LockedAccount(crate::ClientId(1))
+            ))
+        ));
+    }
+
+    #[test]
+    fn tab_delimiter() {
+        let sample_operation = "type,client,tx,amount
+        deposit,1,1,2.0
+        withdrawal,1,2,0.5
+        dispute,1,1,";
+        let config = crate::Config {
+            delimiter: b'\t',
+            ..Default::default()
+        };
+        assert_eq!(
+            try_input(sample_operation),
+            try_input_with(&sample_operation.replace(',', "\t"), &config)
+        );
+        assert_eq!(
+            "client,available,held,total,locked\n1,-0.5,2.0,1.5,false\n",
+            std::str::from_utf8(&try_input_with(
+                &sample_operation.replace(',', "\t"),
+                &config
+            ))
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn non_finite_amount() {
+        let mut accounts = crate::ClientAccounts::new();
+        let mut operations_register = crate::MoneyOperationsRegister::new();
+        for (id, amount) in ["inf", "nan"].iter().enumerate() {
+            let line = crate::TransactionLine {
+                transaction_type: crate::TransactionKind::Deposit,
+                client_id: crate::ClientId(1),
+                transaction_id: crate::TransactionId(id as u32),
+                amount: Some(amount.to_string()),
+                currency: None,
+                timestamp: None,
+                metadata: Default::default(),
+            };
+            assert!(matches!(
+                crate::process_line(
+                    line,
+                    &mut accounts,
+                    &mut operations_register,
+                    &crate::Config::default(),
+                    None
+                ),
+                Err(crate::Error::NonFiniteAmount)
+            ));
+        }
+
+        let sample_operation = "type, 		client,	tx,	amount
+        deposit,	1,	1,	inf
+        deposit,	1,	2,	nan
+        deposit,	1,	3,	1.0";
+        let output = try_input(sample_operation);
+        assert_eq!(
+            "client,available,held,total,locked\n1,1.0,0.0,1.0,false\n",
+            std::str::from_utf8(&output).unwrap()
+        );
+    }
+
+    #[test]
+    fn overdraft() {
+        let sample_operation = "type, 		client,	tx,	amount
+        deposit,	1,	1,	2.0
+        deposit,	2,	2,	2.0
+        withdrawal,	1,	3,	5.0
+        withdrawal,	2,	4,	5.0";
+        let config = crate::Config {
+            allow_overdraft: [crate::ClientId(1)].iter().copied().collect(),
+            ..Default::default()
+        };
+        let output = try_input_with(sample_operation, &config);
+        assert_eq!(
+            "client,available,held,total,locked\n1,-3.0,0.0,-3.0,false\n2,2.0,0.0,2.0,false\n",
+            std::str::from_utf8(&output).unwrap()
+        );
+    }
+
+    #[test]
+    fn held_overflow() {
+        let sample_operation = "type,client,tx,amount
+deposit,1,1,900000000000000
+withdrawal,1,2,900000000000000
+deposit,1,3,900000000000000
+withdrawal,1,4,900000000000000
+dispute,1,1,
+dispute,1,3,";
+        assert_eq!(
+            "client,available,held,total,locked\n1,-900000000000000.0,900000000000000.0,0.0,false\n",
+            std::str::from_utf8(&try_input(sample_operation)).unwrap()
+        );
+    }
+
+    #[test]
+    fn overdraft_overflow() {
+        let sample_operation = "type,client,tx,amount
+deposit,1,1,1.0
+withdrawal,1,2,900000000000000
+withdrawal,1,3,900000000000000";
+        let config = crate::Config {
+            allow_overdraft: [crate::ClientId(1)].iter().copied().collect(),
+            ..Default::default()
+        };
+        let output = try_input_with(sample_operation, &config);
+        assert_eq!(
+            "client,available,held,total,locked\n1,-899999999999999.0,0.0,-899999999999999.0,false\n",
+            std::str::from_utf8(&output).unwrap()
+        );
+    }
+
+    #[test]
+    fn display_order() {
+        use std::convert::TryFrom;
+
+        let line = crate::TransactionLine {
+            transaction_type: crate::TransactionKind::Deposit,
+            client_id: crate::ClientId(1),
+            transaction_id: crate::TransactionId(3),
+            amount: Some(String::from("2")),
+            currency: None,
+            timestamp: None,
+            metadata: Default::default(),
+        };
+        let order = crate::TransactionOrder::try_from(line).unwrap();
+        assert_eq!("deposit client=1 tx=3 amount=2.0", order.to_string());
+    }
+
+    #[test]
+    fn validate_file() {
+        let sample_operation = "type,client,tx,amount
+deposit,1,1,2.0
+deposit,x,2,2.0
+withdrawal,1,3,5.0
+dispute,1,1,
+withdrawal,2,4,1.0
+resolve,1,1,";
+        let errors = crate::validate_file(sample_operation.as_bytes(), &crate::Config::default());
+        assert_eq!(3, errors.len());
+        assert!(matches!(errors[0], (3, crate::Error::InvalidId { .. })));
+        assert!(matches!(
+            errors[1],
+            (
+                4,
+                crate::Error::TransactionError(crate::TransactionError::NotEnoughFunds)
+            )
+        ));
+        assert!(matches!(
+            errors[2],
+            (
+                6,
+                crate::Error::TransactionError(crate::TransactionError::MissingClient(_))
+            )
+        ));
+    }
+
+    #[test]
+    fn volume() {
+        let sample_operation = "type, 		client,	tx,	amount
+        deposit,	1,	1,	10.0
+        withdrawal,	1,	2,	3.0
+        dispute,	1,	1,
+        chargeback,	1,	1,";
+        let mut accounts = crate::ClientAccounts::new();
+        let mut operations_register = crate::MoneyOperationsRegister::new();
+        crate::read_transactions_file(
+            sample_operation.as_bytes(),
+            &mut accounts,
+            &mut operations_register,
+            &crate::Config::default(),
+            None,
+            None,
+            &crate::NoMetrics,
+        )
+        .unwrap();
+        assert_eq!(
+            (crate::Amount::from("10"), crate::Amount::from("3")),
+            accounts.get_account(crate::ClientId(1)).unwrap().volume()
+        );
+        accounts.set_volume_columns(true);
+        let mut buf = Vec::new();
+        accounts.print_to(&mut buf).unwrap();
+        assert_eq!(
+            "client,available,held,total,locked,deposited,withdrawn\n1,-3.0,0.0,-3.0,true,10.0,3.0\n",
+            std::str::from_utf8(&buf).unwrap()
+        );
+    }
+
+    #[test]
+    fn from_snapshot() {
+        let mut accounts = crate::ClientAccounts::from_snapshot(vec![(
+            crate::ClientId(1),
+            crate::Amount::from("5"),
+            crate::Amount::from("1"),
+            true,
+        )]);
+        let mut operations_register = crate::MoneyOperationsRegister::new();
+        let deposit = crate::TransactionLine {
+            transaction_type: crate::TransactionKind::Deposit,
+            client_id: crate::ClientId(1),
+            transaction_id: crate::TransactionId(1),
+            amount: Some(String::from("2")),
+            currency: None,
+            timestamp: None,
+            metadata: Default::default(),
+        };
+        assert!(matches!(
+            crate::process_line(
+                deposit,
+                &mut accounts,
+                &mut operations_register,
+                &crate::Config::default(),
+                None
+            ),
+            Err(crate::Error::TransactionError(
+                crate::TransactionError::LockedAccount(crate::ClientId(1))
+            ))
+        ));
+        let mut buf = Vec::new();
+        accounts.print_to(&mut buf).unwrap();
+        assert_eq!(
+            "client,available,held,total,locked\n1,5.0,1.0,6.0,true\n",
+            std::str::from_utf8(&buf).unwrap()
+        );
+    }
+
+    #[test]
+    fn state_round_trip() {
+        let first_half = "type, 		client,	tx,	amount
+        deposit,	1,	1,	10.0
+        deposit,	2,	2,	5.0
+        dispute,	1,	1,
+        dispute,	2,	2,
+        resolve,	2,	2,";
+        let second_half = "type, 		client,	tx,	amount
+        chargeback,	1,	1,
+        deposit,	2,	1,	3.0
+        withdrawal,	2,	3,	1.5
+        dispute,	2,	3,
+        resolve,	2,	2,
+        dispute,	2,	2,";
+        let single_pass = try_input(&format!(
+            "{}{}",
+            first_half,
+            second_half.trim_start_matches(|c| c != '\n')
+        ));
+
+        let mut accounts = crate::ClientAccounts::new();
+        let mut operations_register = crate::MoneyOperationsRegister::new();
+        crate::read_transactions_file(
+            first_half.as_bytes(),
+            &mut accounts,
+            &mut operations_register,
+            &crate::Config::default(),
+            None,
+            None,
+            &crate::NoMetrics,
+        )
+        .unwrap();
+        let mut state = Vec::new();
+        crate::save_state(&mut state, &accounts, &operations_register).unwrap();
+        let (mut accounts, mut operations_register) = crate::load_state(state.as_slice()).unwrap();
+        crate::read_transactions_file(
+            second_half.as_bytes(),
+            &mut accounts,
+            &mut operations_register,
+            &crate::Config::default(),
+            None,
+            None,
+            &crate::NoMetrics,
+        )
+        .unwrap();
+        let mut resumed = Vec::new();
+        accounts.print_to(&mut resumed).unwrap();
+        assert_eq!(
+            std::str::from_utf8(&single_pass).unwrap(),
+            std::str::from_utf8(&resumed).unwrap()
+        );
+        assert_eq!(
+            "client,available,held,total,locked\n1,0.0,0.0,0.0,true\n2,-1.5,6.5,5.0,false\n",
+            std::str::from_utf8(&resumed).unwrap()
+        );
+    }
+
+    #[test]
+    fn resume_limits() {
+        let config = crate::Config {
+            max_operations_per_client: Some(2),
+            register_capacity: Some(1),
+            ..Default::default()
+        };
+        let mut accounts = crate::ClientAccounts::new();
+        let mut operations_register = crate::MoneyOperationsRegister::from_config(&config);
+        crate::read_transactions_file(
+            "type,client,tx,amount
+deposit,1,1,1.0
+deposit,1,2,2.0"
+                .as_bytes(),
+            &mut accounts,
+            &mut operations_register,
+            &config,
+            None,
+            None,
+            &crate::NoMetrics,
+        )
+        .unwrap();
+        let mut state = Vec::new();
+        crate::save_state(&mut state, &accounts, &operations_register).unwrap();
+        let (mut accounts, mut operations_register) = crate::load_state(state.as_slice()).unwrap();
+        let mut errors = Vec::new();
+        crate::read_transactions_file(
+            "type,client,tx,amount
+deposit,1,3,4.0
+deposit,2,4,1.0
+dispute,1,2,"
+                .as_bytes(),
+            &mut accounts,
+            &mut operations_register,
+            &config,
+            None,
+            Some(&mut errors),
+            &crate::NoMetrics,
+        )
+        .unwrap();
+        let errors = std::str::from_utf8(&errors).unwrap();
+        assert!(errors.starts_with("line 2: Client 1 made too many operations\n"));
+        assert_eq!(2, errors.lines().count());
+        let mut output = Vec::new();
+        accounts.print_to(&mut output).unwrap();
+        assert_eq!(
+            "client,available,held,total,locked\n1,3.0,0.0,3.0,false\n2,1.0,0.0,1.0,false\n",
+            std::str::from_utf8(&output).unwrap()
+        );
+    }
+
+    #[test]
+    fn error_line_numbers() {
+        let sample_operation = "type,client,tx,amount
+deposit,1,1,2.0
+withdrawal,1,2,5.0";
+        let mut accounts = crate::ClientAccounts::new();
+        let mut operations_register = crate::MoneyOperationsRegister::new();
+        let mut error_output = Vec::new();
+        crate::read_transactions_file(
+            sample_operation.as_bytes(),
+            &mut accounts,
+            &mut operations_register,
+            &crate::Config::default(),
+            None,
+            Some(&mut error_output),
+            &crate::NoMetrics,
+        )
+        .unwrap();
+        assert_eq!(
+            "line 3: Not enough funds\n",
+            std::str::from_utf8(&error_output).unwrap()
+        );
+    }
+
+    #[test]
+    fn error_output() {
+        let sample_operation = "type, 		client,	tx,	amount
+        deposit,	1,	1,	2.0
+        withdrawal,	1,	2,	5.0
+        withdrawal,	1,	3,	1.0";
+        let mut accounts = crate::ClientAccounts::new();
+        let mut operations_register = crate::MoneyOperationsRegister::new();
+        let mut error_output = Vec::new();
+        crate::read_transactions_file(
+            sample_operation.as_bytes(),
+            &mut accounts,
+            &mut operations_register,
+            &crate::Config::default(),
+            None,
+            Some(&mut error_output),
+            &crate::NoMetrics,
+        )
+        .unwrap();
+        assert_eq!(b"line 3: Not enough funds\n", error_output.as_slice());
+    }
+
+    #[test]
+    fn claim_with_amount() {
+        let sample_operation = "type, 		client,	tx,	amount
+        deposit,	1,	1,	5.0
+        resolve, 1, 1, 5.0";
+        let output = try_input(sample_operation);
+        assert_eq!(
+            "client,available,held,total,locked\n1,5.0,0.0,5.0,false\n",
+            std::str::from_utf8(&output).unwrap()
+        );
+        let errors = crate::validate_file(sample_operation.as_bytes(), &crate::Config::default());
+        assert!(matches!(
+            errors.as_slice(),
+            [(3, crate::Error::WrongArgument)]
+        ));
+    }
+
+    #[test]
+    fn multiple_files() {
+        let first_file = "type,client,tx,amount
+deposit,1,1,10.0
+deposit,2,2,5.0";
+        let second_file = "type,client,tx,amount
+dispute,1,1,
+withdrawal,2,3,2.0";
+        let mut accounts = crate::ClientAccounts::new();
+        let mut operations_register = crate::MoneyOperationsRegister::new();
+        for file in [first_file, second_file].iter() {
+            crate::read_transactions_file(
+                file.as_bytes(),
+                &mut accounts,
+                &mut operations_register,
+                &crate::Config::default(),
+                None,
+                None,
+                &crate::NoMetrics,
+            )
+            .unwrap();
+        }
+        let mut buf = Vec::new();
+        accounts.print_to(&mut buf).unwrap();
+        let concatenated = format!(
+            "{}\n{}",
+            first_file,
+            second_file.lines().skip(1).collect::<Vec<_>>().join("\n")
+        );
+        assert_eq!(try_input(&concatenated), buf);
+
+        let (accounts, _) = crate::process_parallel(
+            vec![first_file.as_bytes(), second_file.as_bytes()],
+            2,
+            &crate::Config::default(),
+        )
+        .unwrap();
+        let mut parallel = Vec::new();
+        accounts.print_to(&mut parallel).unwrap();
+        assert_eq!(buf, parallel);
+    }
+
+    #[test]
+    fn unlock() {
+        let sample_operation = "type,client,tx,amount
+deposit,1,1,10.0
+dispute,1,1,
+chargeback,1,1,
+deposit,1,2,3.0";
+        let mut accounts = crate::ClientAccounts::new();
+        let mut operations_register = crate::MoneyOperationsRegister::new();
+        crate::read_transactions_file(
+            sample_operation.as_bytes(),
+            &mut accounts,
+            &mut operations_register,
+            &crate::Config::default(),
+            None,
+            None,
+            &crate::NoMetrics,
+        )
+        .unwrap();
+        let client = accounts.get_account(crate::ClientId(1)).unwrap();
+        assert!(client.locked);
+        assert_eq!(
+            Some(crate::LockReason::Chargeback(crate::TransactionId(1))),
+            client.lock_reason()
+        );
+        let report = crate::read_transactions_file(
+            "type,client,tx,amount
+unlock,1,1,
+unlock,1,1,
+deposit,1,3,3.0"
+                .as_bytes(),
+            &mut accounts,
+            &mut operations_register,
+            &crate::Config::default(),
+            None,
+            None,
+            &crate::NoMetrics,
+        )
+        .unwrap();
+        assert_eq!(1, report.rejected());
+        let client = accounts.get_account(crate::ClientId(1)).unwrap();
+        assert!(!client.locked);
+        assert_eq!(None, client.lock_reason());
+        assert_eq!(
+            Some(crate::Amount::from("3.0")),
+            accounts
+                .balance_of(crate::ClientId(1))
+                .map(|summary| summary.available)
+        );
+    }
+
+    #[test]
+    fn iter_accounts() {
+        let sample_operation = "type,client,tx,amount
+deposit,1,1,10.0
+deposit,2,2,5.5
+deposit,3,3,1.25
+dispute,1,1,
+dispute,2,2,
+dispute,3,3,";
+        let mut accounts = crate::ClientAccounts::new();
+        crate::read_transactions_file(
+            sample_operation.as_bytes(),
+            &mut accounts,
+            &mut crate::MoneyOperationsRegister::new(),
+            &crate::Config::default(),
+            None,
+            None,
+            &crate::NoMetrics,
+        )
+        .unwrap();
+        let held = accounts
+            .iter()
+            .fold(crate::Amount::ZERO, |total, (_, client)| {
+                total.checked_add(client.held()).unwrap()
+            });
+        assert_eq!(crate::Amount::from("16.75"), held);
+        assert!(accounts
+            .iter()
+            .all(|(_, client)| client.available() == crate::Amount::ZERO));
+    }
+
+    #[test]
+    fn dispute_policy() {
+        let sample_operation = "type,client,tx,amount
+deposit,1,1,10.0
+withdrawal,1,2,8.0
+dispute,1,1,";
+        let output = try_input(sample_operation);
+        assert_eq!(
+            "client,available,held,total,locked\n1,-8.0,10.0,2.0,false\n",
+            std::str::from_utf8(&output).unwrap()
+        );
+        let config = crate::Config {
+            dispute_policy: crate::DisputePolicy::RejectIfNegative,
+            ..Default::default()
+        };
+        let output = try_input_with(sample_operation, &config);
+        assert_eq!(
+            "client,available,held,total,locked\n1,2.0,0.0,2.0,false\n",
+            std::str::from_utf8(&output).unwrap()
+        );
+        let mut accounts = crate::ClientAccounts::new();
+        let report = crate::read_transactions_file(
+            sample_operation.as_bytes(),
+            &mut accounts,
+            &mut crate::MoneyOperationsRegister::new(),
+            &config,
+            None,
+            None,
+            &crate::NoMetrics,
+        )
+        .unwrap();
+        assert_eq!(Some(&1), report.errors.get("WouldGoNegative"));
+    }
+
+    #[test]
+    fn partial_dispute() {
+        let sample_operation = "type,client,tx,amount
+deposit,1,1,50.0
+dispute,1,1,30.0";
+        let output = try_input(sample_operation);
+        assert_eq!(
+            "client,available,held,total,locked\n1,20.0,30.0,50.0,false\n",
+            std::str::from_utf8(&output).unwrap()
+        );
+        let output = try_input(&format!("{}\nresolve,1,1,", sample_operation));
+        assert_eq!(
+            "client,available,held,total,locked\n1,50.0,0.0,50.0,false\n",
+            std::str::from_utf8(&output).unwrap()
+        );
+        let output = try_input(&format!(
+            "{}\ndispute,1,1,25.0\ndispute,1,1,20.0\nchargeback,1,1,",
+            sample_operation
+        ));
+        assert_eq!(
+            "client,available,held,total,locked\n1,0.0,0.0,0.0,true\n",
+            std::str::from_utf8(&output).unwrap()
+        );
+    }
+
+    #[test]
+    fn every_kind_converts() {
+        use {std::convert::TryFrom, strum::IntoEnumIterator};
+        let line = |transaction_type, amount: Option<&str>| crate::TransactionLine {
+            transaction_type,
+            client_id: crate::ClientId(1),
+            transaction_id: crate::TransactionId(1),
+            amount: amount.map(String::from),
+            currency: None,
+            timestamp: None,
+            metadata: Default::default(),
+        };
+        for kind in crate::TransactionKind::iter() {
+            // Malformed amounts are rejected without panicking
+            for amount in [Some("-1.0"), Some("x")].iter() {
+                let _ = crate::TransactionOrder::try_from(line(kind, *amount));
+            }
+            // Each kind takes an amount or not
+            let converted = [None, Some("1.0")]
+                .iter()
+                .find_map(|amount| crate::TransactionOrder::try_from(line(kind, *amount)).ok());
+            assert_eq!(Some(kind), converted.map(|order| order.kind()), "{}", kind);
+        }
+    }
+
+    struct CapturingLogger;
+
+    static RECORDS: std::sync::Mutex<Vec<(log::Level, String)>> = std::sync::Mutex::new(Vec::new());
+
+    impl log::Log for CapturingLogger {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+        fn log(&self, record: &log::Record) {
+            RECORDS
+                .lock()
+                .unwrap()
+                .push((record.level(), record.args().to_string()));
+        }
+        fn flush(&self) {}
+    }
+
+    #[test]
+    fn logging() {
+        static LOGGER: CapturingLogger = CapturingLogger;
+        let _ = log::set_logger(&LOGGER);
+        log::set_max_level(log::LevelFilter::Trace);
+        let sample_operation = "type,client,tx,amount
+deposit,7001,1,1.0
+withdrawal,7001,2,3.0";
+        try_input(sample_operation);
+        let records = RECORDS.lock().unwrap();
+        assert!(records
+            .iter()
+            .any(|(level, message)| *level == log::Level::Debug
+                && message == "deposit client=7001 tx=1 amount=1.0"));
+        assert!(records
+            .iter()
+            .any(|(level, message)| *level == log::Level::Warn
+                && message == "line 3: Not enough funds"));
+    }
+
+    #[test]
+    fn register_capacity() {
+        let sample_operation = "type,client,tx,amount
+deposit,1,1,1.0
+deposit,1,2,2.0
+dispute,1,2,
+deposit,1,3,3.0
+deposit,1,4,4.0
+dispute,1,1,
+resolve,1,2,";
+        let mut accounts = crate::ClientAccounts::new();
+        let mut operations_register = crate::MoneyOperationsRegister::with_capacity(2);
+        let mut errors = Vec::new();
+        crate::read_transactions_file(
+            sample_operation.as_bytes(),
+            &mut accounts,
+            &mut operations_register,
+            &crate::Config::default(),
+            None,
+            Some(&mut errors),
+            &crate::NoMetrics,
+        )
+        .unwrap();
+        assert_eq!(
+            "line 7: Can't find transaction 1\n",
+            std::str::from_utf8(&errors).unwrap()
+        );
+        assert!(!operations_register.contains(crate::ClientId(1), crate::TransactionId(3)));
+        assert!(operations_register.contains(crate::ClientId(1), crate::TransactionId(4)));
+    }
+
+    #[test]
+    fn evicted_ids_are_not_reused() {
+        let sample_operation = "type,client,tx,amount
+deposit,1,1,1.0
+deposit,1,2,2.0
+deposit,1,1,3.0";
+        let mut accounts = crate::ClientAccounts::new();
+        let mut operations_register = crate::MoneyOperationsRegister::with_capacity(1);
+        let mut errors = Vec::new();
+        crate::read_transactions_file(
+            sample_operation.as_bytes(),
+            &mut accounts,
+            &mut operations_register,
+            &crate::Config::default(),
+            None,
+            Some(&mut errors),
+            &crate::NoMetrics,
+        )
+        .unwrap();
+        assert_eq!(
+            "line 4: Transaction 1 already exists\n",
+            std::str::from_utf8(&errors).unwrap()
+        );
+        assert!(!operations_register.contains(crate::ClientId(1), crate::TransactionId(1)));
+        // The evicted ids are kept by a resumed run
+        let mut state = Vec::new();
+        crate::save_state(&mut state, &accounts, &operations_register).unwrap();
+        let (mut accounts, mut operations_register) = crate::load_state(state.as_slice()).unwrap();
+        let result = crate::read_transactions_file(
+            "type,client,tx,amount\ndeposit,1,1,3.0".as_bytes(),
+            &mut accounts,
+            &mut operations_register,
+            &crate::Config::default(),
+            None,
+            None,
+            &crate::NoMetrics,
+        )
+        .unwrap();
+        assert_eq!(1, result.rejected());
+        assert_eq!(
+            crate::Amount::from("3.0"),
+            accounts.balance_of(crate::ClientId(1)).unwrap().total
+        );
+    }
+
+    #[test]
+    fn json_array_output() {
+        let mut accounts = crate::ClientAccounts::new();
+        accounts.create_client(crate::ClientId(2), crate::Amount::from("1.5"));
+        accounts.create_client(crate::ClientId(1), crate::Amount::from("2.2342"));
+        let mut buf = Vec::new();
+        accounts.print_json_array_to(&mut buf).unwrap();
+        assert_eq!(
+            concat!(
+                r#"[{"client":1,"available":2.2342,"held":0.0,"total":2.2342,"locked":false},"#,
+                r#"{"client":2,"available":1.5,"held":0.0,"total":1.5,"locked":false}]"#,
+                "\n"
+            ),
+            std::str::from_utf8(&buf).unwrap()
+        );
+        let mut buf = Vec::new();
+        crate::ClientAccounts::new()
+            .print_json_array_to(&mut buf)
+            .unwrap();
+        assert_eq!("[]\n", std::str::from_utf8(&buf).unwrap());
+    }
+
+    #[test]
+    fn currencies() {
+        let sample_operation = "type,client,tx,amount,currency
+deposit,1,1,10.0,USD
+deposit,1,2,5.0,EUR
+withdrawal,1,3,7.0,EUR
+withdrawal,1,4,7.0,USD
+dispute,1,2,";
+        let output = try_input(sample_operation);
+        assert_eq!(
+            "client,currency,available,held,total,locked\n1,EUR,0.0,5.0,5.0,false\n1,USD,3.0,0.0,3.0,false\n",
+            std::str::from_utf8(&output).unwrap()
+        );
+    }
+
+    #[test]
+    fn withdrawal_chargeback_balances() {
+        let mut accounts = crate::ClientAccounts::new();
+        let mut operations_register = crate::MoneyOperationsRegister::new();
+        let steps = [
+            ("deposit,1,1,10.0", "10.0", "0.0"),
+            ("withdrawal,1,2,4.0", "6.0", "0.0"),
+            ("dispute,1,2,1.5", "6.0", "1.5"),
+            ("dispute,1,2,2.5", "6.0", "4.0"),
+            ("chargeback,1,2,", "10.0", "0.0"),
+        ];
+        for (line, available, held) in steps.iter() {
+            let input = format!("type,client,tx,amount\n{}", line);
+            let report = crate::read_transactions_file(
+                input.as_bytes(),
+                &mut accounts,
+                &mut operations_register,
+                &crate::Config::default(),
+                None,
+                None,
+                &crate::NoMetrics,
+            )
+            .unwrap();
+            assert_eq!(0, report.rejected());
+            let summary = accounts.balance_of(crate::ClientId(1)).unwrap();
+            assert_eq!(
+                (crate::Amount::from(*available), crate::Amount::from(*held)),
+                (summary.available, summary.held),
+                "after {}",
+                line
+            );
+        }
+        assert!(accounts.balance_of(crate::ClientId(1)).unwrap().locked);
+    }
+
+    #[test]
+    fn verify_invariants() {
+        let mut accounts = crate::ClientAccounts::new();
+        for id in 1..=3 {
+            accounts.create_client(crate::ClientId(id), crate::Amount::from("1"));
+        }
+        assert_eq!(Ok(()), accounts.verify_invariants());
+        if let Some(client) = accounts.get_account(crate::ClientId(2)) {
+            client.set_held(crate::Amount::from("-1"));
+        }
+        if let Some(client) = accounts.get_account(crate::ClientId(3)) {
+            client.set_held(crate::Amount::MAX);
+        }
+        assert_eq!(
+            Err(vec![
+                crate::InvariantViolation::NegativeHeld(crate::ClientId(2)),
+                crate::InvariantViolation::TotalOverflow(crate::ClientId(3)),
+            ]),
+            accounts.verify_invariants()
+        );
+    }
+
+    #[test]
+    fn withdraw_whole_balance() {
+        let sample_operation = "type,client,tx,amount
+deposit,1,1,0.1
+deposit,1,2,0.1
+deposit,1,3,0.1
+withdrawal,1,4,0.3";
+        let output = try_input(sample_operation);
+        assert_eq!(
+            "client,available,held,total,locked\n1,0.0,0.0,0.0,false\n",
+            std::str::from_utf8(&output).unwrap()
+        );
+    }
+
+    #[derive(Default)]
+    struct CountingMetrics {
+        deposits: std::cell::Cell<usize>,
+        rejected: std::cell::Cell<usize>,
+        clients: std::cell::Cell<usize>,
+    }
+
+    impl crate::Metrics for CountingMetrics {
+        fn transaction_processed(&self, kind: crate::TransactionKind) {
+            if let crate::TransactionKind::Deposit = kind {
+                self.deposits.set(self.deposits.get() + 1);
+            }
+        }
+        fn transaction_rejected(&self, _category: &'static str) {
+            self.rejected.set(self.rejected.get() + 1);
+        }
+        fn active_clients(&self, count: usize) {
+            self.clients.set(count);
+        }
+    }
+
+    #[test]
+    fn metrics() {
+        let sample_operation = "type,client,tx,amount
+deposit,1,1,1.0
+deposit,2,2,1.0
+withdrawal,2,3,2.0";
+        let metrics = CountingMetrics::default();
+        crate::read_transactions_file(
+            sample_operation.as_bytes(),
+            &mut crate::ClientAccounts::new(),
+            &mut crate::MoneyOperationsRegister::new(),
+            &crate::Config::default(),
+            None,
+            None,
+            &metrics,
+        )
+        .unwrap();
+        assert_eq!(2, metrics.deposits.get());
+        assert_eq!(1, metrics.rejected.get());
+        assert_eq!(2, metrics.clients.get());
+    }
+
+    #[test]
+    fn disputable_kinds() {
+        let sample_operation = "type,client,tx,amount
+deposit,1,1,10.0
+withdrawal,1,2,4.0
+dispute,1,2,
+dispute,1,1,";
+        let output = try_input(sample_operation);
+        assert_eq!(
+            "client,available,held,total,locked\n1,-4.0,14.0,10.0,false\n",
+            std::str::from_utf8(&output).unwrap()
+        );
+        let config = crate::Config {
+            disputable_kinds: [crate::TransactionKind::Deposit].iter().copied().collect(),
+            ..Default::default()
+        };
+        let mut errors = Vec::new();
+        crate::read_transactions_file(
+            sample_operation.as_bytes(),
+            &mut crate::ClientAccounts::new(),
+            &mut crate::MoneyOperationsRegister::new(),
+            &config,
+            None,
+            Some(&mut errors),
+            &crate::NoMetrics,
+        )
+        .unwrap();
+        assert_eq!(
+            "line 4: Transaction 2 can't be disputed\n",
+            std::str::from_utf8(&errors).unwrap()
+        );
+        let output = try_input_with(sample_operation, &config);
+        assert_eq!(
+            "client,available,held,total,locked\n1,-4.0,10.0,6.0,false\n",
+            std::str::from_utf8(&output).unwrap()
+        );
+    }
+
+    #[test]
+    fn header_only_output() {
+        let mut accounts = crate::ClientAccounts::new();
+        let mut buf = Vec::new();
+        accounts.print_to(&mut buf).unwrap();
+        assert_eq!(
+            "client,available,held,total,locked\n",
+            std::str::from_utf8(&buf).unwrap()
+        );
+        accounts.set_volume_columns(true);
+        let mut buf = Vec::new();
+        accounts.print_to(&mut buf).unwrap();
+        assert_eq!(
+            "client,available,held,total,locked,deposited,withdrawn\n",
+            std::str::from_utf8(&buf).unwrap()
+        );
+    }
+
+    #[test]
+    fn summary_between() {
+        let sample_operation = "type,client,tx,amount,currency,timestamp
+deposit,1,1,10.0,,2022-01-01T10:00:00Z
+deposit,1,2,5.0,,2022-02-01T10:00:00+02:00
+deposit,1,3,1.0,,";
+        let mut accounts = crate::ClientAccounts::new();
+        let mut operations_register = crate::MoneyOperationsRegister::new();
+        crate::read_transactions_file(
+            sample_operation.as_bytes(),
+            &mut accounts,
+            &mut operations_register,
+            &crate::Config::default(),
+            None,
+            None,
+            &crate::NoMetrics,
+        )
+        .unwrap();
+        let date = |date: &str| {
+            chrono::DateTime::parse_from_rfc3339(date)
+                .unwrap()
+                .with_timezone(&chrono::Utc)
+        };
+        let summaries = accounts
+            .summary_between(date("2022-01-15T00:00:00Z"), date("2022-03-01T00:00:00Z"))
+            .unwrap();
+        assert_eq!(1, summaries.len());
+        assert_eq!(crate::Amount::from("5.0"), summaries[0].total);
+        assert_eq!(
+            crate::Amount::from("16.0"),
+            accounts.balance_of(crate::ClientId(1)).unwrap().total
+        );
+        // The history is kept by a resumed run
+        let mut state = Vec::new();
+        crate::save_state(&mut state, &accounts, &operations_register).unwrap();
+        let (accounts, _) = crate::load_state(state.as_slice()).unwrap();
+        let summaries = accounts
+            .summary_between(date("2022-01-15T00:00:00Z"), date("2022-03-01T00:00:00Z"))
+            .unwrap();
+        assert_eq!(1, summaries.len());
+        assert_eq!(crate::Amount::from("5.0"), summaries[0].total);
+    }
+
+    #[test]
+    fn summary_between_currencies() {
+        let sample_operation = "type,client,tx,amount,currency,timestamp
+deposit,1,1,10.0,EUR,2022-01-01T10:00:00Z
+deposit,1,2,5.0,USD,2022-01-02T10:00:00Z
+withdrawal,1,3,2.0,EUR,2022-01-03T10:00:00Z";
+        let mut accounts = crate::ClientAccounts::new();
+        let mut operations_register = crate::MoneyOperationsRegister::new();
+        crate::read_transactions_file(
+            sample_operation.as_bytes(),
+            &mut accounts,
+            &mut operations_register,
+            &crate::Config::default(),
+            None,
+            None,
+            &crate::NoMetrics,
+        )
+        .unwrap();
+        let date = |date: &str| {
+            chrono::DateTime::parse_from_rfc3339(date)
+                .unwrap()
+                .with_timezone(&chrono::Utc)
+        };
+        let summaries = accounts
+            .summary_between(date("2022-01-01T00:00:00Z"), date("2022-02-01T00:00:00Z"))
+            .unwrap();
+        assert_eq!(2, summaries.len());
+        assert_eq!(
+            Some(crate::Currency("EUR".to_string())),
+            summaries[0].currency
+        );
+        assert_eq!(crate::Amount::from("8.0"), summaries[0].total);
+        assert_eq!(
+            Some(crate::Currency("USD".to_string())),
+            summaries[1].currency
+        );
+        assert_eq!(crate::Amount::from("5.0"), summaries[1].total);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn process_stream() {
+        let line = |transaction_type, transaction_id, amount: &str| crate::TransactionLine {
+            transaction_type,
+            client_id: crate::ClientId(1),
+            transaction_id: crate::TransactionId(transaction_id),
+            amount: Some(amount.to_string()),
+            currency: None,
+            timestamp: None,
+            metadata: Default::default(),
+        };
+        let lines = vec![
+            line(crate::TransactionKind::Deposit, 1, "10.0"),
+            line(crate::TransactionKind::Withdrawal, 2, "3.5"),
+            line(crate::TransactionKind::Withdrawal, 3, "7.0"),
+        ];
+        let mut accounts = crate::ClientAccounts::new();
+        let report = crate::process_stream(
+            futures::stream::iter(lines),
+            &mut accounts,
+            &mut crate::MoneyOperationsRegister::new(),
+            &crate::Config::default(),
+        )
+        .await;
+        assert_eq!(1, report.rejected());
+        assert_eq!(
+            crate::Amount::from("6.5"),
+            accounts.balance_of(crate::ClientId(1)).unwrap().available
+        );
+    }
+
+    #[test]
+    fn zero_amount() {
+        let sample_operation = "type, 		client,	tx,	amount
+        deposit, 1, 1, 0.0";
+        let output = try_input(sample_operation);
+        assert_eq!(
+            "client,available,held,total,locked\n",
+            std::str::from_utf8(&output).unwrap()
+        );
+        let config = crate::Config {
+            allow_zero_amounts: true,
+            ..Default::default()
+        };
+        let output = try_input_with(sample_operation, &config);
+        assert_eq!(
+            "client,available,held,total,locked\n1,0.0,0.0,0.0,false\n",
+            std::str::from_utf8(&output).unwrap()
+        );
+    }
+
+    #[test]
+    fn withdrawal_fee() {
+        let sample_operation = "type,client,tx,amount
+deposit,1,1,10.0
+withdrawal,1,2,4.0";
+        let config = crate::Config {
+            fee_policy: crate::FeePolicy::Flat(crate::Amount::from("1.0")),
+            fee_collector: crate::ClientId(9),
+            ..Default::default()
+        };
+        let output = try_input_with(sample_operation, &config);
+        assert_eq!(
+            "client,available,held,total,locked\n1,5.0,0.0,5.0,false\n9,1.0,0.0,1.0,false\n",
+            std::str::from_utf8(&output).unwrap()
+        );
+        let config = crate::Config {
+            fee_policy: crate::FeePolicy::Percentage(crate::Amount::from("2.5")),
+            fee_collector: crate::ClientId(9),
+            ..Default::default()
+        };
+        let output = try_input_with(sample_operation, &config);
+        assert_eq!(
+            "client,available,held,total,locked\n1,5.9,0.0,5.9,false\n9,0.1,0.0,0.1,false\n",
+            std::str::from_utf8(&output).unwrap()
+        );
+        // The fee must be covered as well
+        let output = try_input_with(&sample_operation.replace("4.0", "9.8"), &config);
+        assert_eq!(
+            "client,available,held,total,locked\n1,10.0,0.0,10.0,false\n",
+            std::str::from_utf8(&output).unwrap()
+        );
+    }
+
+    #[test]
+    fn negative_reversal() {
+        let sample_operation = "type, 		client,	tx,	amount
+        deposit, 1, 1, 10.0
+        deposit, 1, 2, -3.0
+        withdrawal, 1, 3, -1.5";
+        let output = try_input(sample_operation);
+        assert_eq!(
+            "client,available,held,total,locked\n1,10.0,0.0,10.0,false\n",
+            std::str::from_utf8(&output).unwrap()
+        );
+        let config = crate::Config {
+            treat_negative_as_reversal: true,
+            ..Default::default()
+        };
+        let output = try_input_with(sample_operation, &config);
+        assert_eq!(
+            "client,available,held,total,locked\n1,8.5,0.0,8.5,false\n",
+            std::str::from_utf8(&output).unwrap()
+        );
+    }
+
+    #[test]
+    fn close_account() {
+        let sample_operation = "type,client,tx,amount
+deposit,1,1,5.0
+deposit,2,2,1.0
+close,1,1,";
+        let output = try_input(sample_operation);
+        assert_eq!(
+            "client,available,held,total,locked\n1,5.0,0.0,5.0,false\n2,1.0,0.0,1.0,false\n",
+            std::str::from_utf8(&output).unwrap()
+        );
+        let output = try_input(&format!(
+            "{}\nwithdrawal,1,3,5.0\nclose,1,3,",
+            sample_operation
+        ));
+        assert_eq!(
+            "client,available,held,total,locked\n2,1.0,0.0,1.0,false\n",
+            std::str::from_utf8(&output).unwrap()
+        );
+    }
+
+    #[test]
+    fn client_round_trip() {
+        let json = r#"{"client":3,"currency":"","available":"-1.2345","held":"2.5","deposited":"3.0","withdrawn":"0.0","locked":true,"lock_reason":{"Chargeback":7}}"#;
+        let client: crate::Client = serde_json::from_str(json).unwrap();
+        assert_eq!(crate::Amount::from("-1.2345"), client.available());
+        assert_eq!(crate::Amount::from("2.5"), client.held());
+        assert_eq!(json, serde_json::to_string(&client).unwrap());
+    }
+
+    #[test]
+    fn missing_amount() {
+        let sample_operation = "type, 		client,	tx,	amount
+        deposit, 1, 1,
+        withdrawal, 1, 2
+        deposit, 1, 3, -1.0";
+        let errors = crate::validate_file(sample_operation.as_bytes(), &crate::Config::default());
+        assert!(matches!(
+            errors.as_slice(),
+            [
+                (2, crate::Error::MissingAmount),
+                (3, crate::Error::MissingAmount),
+                (4, crate::Error::WrongArgument)
+            ]
+        ));
+    }
+
+    #[test]
+    fn output_decimals() {
+        let mut accounts = crate::ClientAccounts::new();
+        accounts.create_client(crate::ClientId(1), crate::Amount::from("1.2355"));
+        accounts.set_decimals(2).unwrap();
+        let mut buf = Vec::new();
+        accounts.print_to(&mut buf).unwrap();
+        assert_eq!(
+            "client,available,held,total,locked\n1,1.24,0.0,1.24,false\n",
+            std::str::from_utf8(&buf).unwrap()
+        );
+        assert_eq!(Err(crate::TooManyDecimals(8)), accounts.set_decimals(8));
+        accounts.set_decimals(4).unwrap();
+        let mut buf = Vec::new();
+        accounts.print_to(&mut buf).unwrap();
+        assert_eq!(
+            "client,available,held,total,locked\n1,1.2355,0.0,1.2355,false\n",
+            std::str::from_utf8(&buf).unwrap()
+        );
+        accounts.set_decimals(0).unwrap();
+        let mut buf = Vec::new();
+        accounts.print_to(&mut buf).unwrap();
+        assert_eq!(
+            "client,available,held,total,locked\n1,1,0,1,false\n",
+            std::str::from_utf8(&buf).unwrap()
+        );
+        let mut buf = Vec::new();
+        accounts.print_json_to(&mut buf).unwrap();
+        assert_eq!(
+            "{\"client\":1,\"available\":1,\"held\":0,\"total\":1,\"locked\":false}\n",
+            std::str::from_utf8(&buf).unwrap()
+        );
+    }
+
+    #[test]
+    fn bad_header() {
+        let sample_operation = "type,clinet,tx,amount,amount
+deposit,1,1,1.0,1.0";
+        let config = crate::Config {
+            strict_headers: true,
+            ..Default::default()
+        };
+        let errors = crate::validate_file(sample_operation.as_bytes(), &config);
+        match errors.as_slice() {
+            [(
+                1,
+                crate::Error::BadHeader {
+                    unexpected,
+                    missing,
+                    duplicated,
+                },
+            )] => {
+                assert_eq!(&["clinet"], unexpected.as_slice());
+                assert_eq!(&["client"], missing.as_slice());
+                assert_eq!(&["amount"], duplicated.as_slice());
+            }
+            errors => panic!("Unexpected errors {:?}", errors),
+        }
+        let valid = "tx,amount,client,type,currency
+1,1.0,1,deposit,EUR";
+        assert!(crate::validate_file(valid.as_bytes(), &config).is_empty());
+    }
+
+    #[test]
+    fn replay_audit() {
+        let sample_operation = "type,client,tx,amount
+deposit,1,1,10.0
+deposit,2,2,5.0
+withdrawal,1,3,2.5
+withdrawal,2,4,8.0
+dispute,1,1,
+chargeback,1,1,
+deposit,3,5,1.0
+dispute,3,5,";
+        let mut accounts = crate::ClientAccounts::new();
+        let mut audit_log = crate::AuditLog::new();
+        crate::read_transactions_file(
+            sample_operation.as_bytes(),
+            &mut accounts,
+            &mut crate::MoneyOperationsRegister::new(),
+            &crate::Config::default(),
+            Some(&mut audit_log),
+            None,
+            &crate::NoMetrics,
+        )
+        .unwrap();
+        let mut log = Vec::new();
+        audit_log.write_csv_to(&mut log).unwrap();
+        let replayed = crate::replay_audit(log.as_slice()).unwrap();
+        let (mut expected, mut output) = (Vec::new(), Vec::new());
+        accounts.print_to(&mut expected).unwrap();
+        replayed.print_to(&mut output).unwrap();
+        assert_eq!(
+            std::str::from_utf8(&expected).unwrap(),
+            std::str::from_utf8(&output).unwrap()
+        );
+    }
+
+    #[test]
+    fn accrue_held() {
+        let sample_operation = "type,client,tx,amount
+deposit,1,1,100.0
+dispute,1,1,
+accrue,1,2,0.1";
+        let output = try_input(sample_operation);
+        assert_eq!(
+            "client,available,held,total,locked\n1,0.0,110.0,110.0,false\n",
+            std::str::from_utf8(&output).unwrap()
+        );
+    }
+
+    #[test]
+    fn accrued_interest_is_released() {
+        let sample_operation = "type,client,tx,amount
+deposit,1,1,100.0
+deposit,1,2,50.0
+dispute,1,1,
+accrue,1,3,0.1
+dispute,1,2,
+resolve,1,1,
+resolve,1,2,";
+        let output = try_input(sample_operation);
+        assert_eq!(
+            "client,available,held,total,locked\n1,160.0,0.0,160.0,false\n",
+            std::str::from_utf8(&output).unwrap()
+        );
+    }
+
+    #[test]
+    fn invalid_ids() {
+        let sample_operation = "type,client,tx,amount
+deposit,abc,1,2.0
+deposit,1,x2,2.0
+deposit,1,3,2.0";
+        let errors = crate::validate_file(sample_operation.as_bytes(), &crate::Config::default());
+        assert_eq!(2, errors.len());
+        assert_eq!("Invalid id in column client: abc", errors[0].1.to_string());
+        assert!(matches!(
+            &errors[0],
+            (2, crate::Error::InvalidId { column, value }) if column == "client" && value == "abc"
+        ));
+        assert_eq!("Invalid id in column tx: x2", errors[1].1.to_string());
+        assert!(matches!(
+            &errors[1],
+            (3, crate::Error::InvalidId { column, value }) if column == "tx" && value == "x2"
+        ));
+    }
+
+    #[test]
+    fn max_operations_per_client() {
+        let sample_operation = "type,client,tx,amount
+deposit,1,1,1.0
+deposit,1,2,2.0
+deposit,2,3,3.0
+deposit,1,4,4.0";
+        let config = crate::Config {
+            max_operations_per_client: Some(2),
+            ..Default::default()
+        };
+        let mut accounts = crate::ClientAccounts::new();
+        let mut operations_register = crate::MoneyOperationsRegister::new();
+        let mut errors = Vec::new();
+        crate::read_transactions_file(
+            sample_operation.as_bytes(),
+            &mut accounts,
+            &mut operations_register,
+            &config,
+            None,
+            Some(&mut errors),
+            &crate::NoMetrics,
+        )
+        .unwrap();
+        assert_eq!(
+            "line 5: Client 1 made too many operations\n",
+            std::str::from_utf8(&errors).unwrap()
+        );
+        assert_eq!(
+            (crate::Amount::from("3.0"), crate::Amount::ZERO),
+            accounts.balances(crate::ClientId(1), &crate::Currency::default())
+        );
+    }
+
+    #[test]
+    fn transaction_results() {
+        let sample_operation = "type,client,tx,amount
+deposit,1,1,2.0
+withdrawal,1,2,5.0
+deposit,1,3,4.0";
+        let mut accounts = crate::ClientAccounts::new();
+        let mut operations_register = crate::MoneyOperationsRegister::new();
+        let config = crate::Config::default();
+        let results: Vec<_> = crate::transaction_results(
+            sample_operation.as_bytes(),
+            &mut accounts,
+            &mut operations_register,
+            &config,
+        )
+        .take(2)
+        .collect();
+        assert!(matches!(results[0], Ok(())));
+        assert!(matches!(
+            results[1],
+            Err(crate::Error::TransactionError(
+                crate::TransactionError::NotEnoughFunds
+            ))
+        ));
+        assert!(!operations_register.contains(crate::ClientId(1), crate::TransactionId(3)));
+        assert_eq!(
+            (crate::Amount::from("2.0"), crate::Amount::ZERO),
+            accounts.balances(crate::ClientId(1), &crate::Currency::default())
+        );
+    }
+
+    #[test]
+    fn anomalies() {
+        let sample_operation = "type,client,tx,amount
+deposit,1,1,10.0
+withdrawal,1,2,8.0
+dispute,1,1,
+deposit,2,3,1.0";
+        let mut accounts = crate::ClientAccounts::new();
+        let mut operations_register = crate::MoneyOperationsRegister::new();
+        crate::read_transactions_file(
+            sample_operation.as_bytes(),
+            &mut accounts,
+            &mut operations_register,
+            &crate::Config::default(),
+            None,
+            None,
+            &crate::NoMetrics,
+        )
+        .unwrap();
+        assert_eq!(
+            vec![(crate::ClientId(1), crate::AnomalyKind::NegativeAvailable)],
+            accounts.anomalies()
+        );
+    }
+
+    #[test]
+    fn column_selection() {
+        let mut accounts = crate::ClientAccounts::new();
+        accounts.create_client(crate::ClientId(1), crate::Amount::from("2.5"));
+        accounts
+            .set_columns(vec![
+                crate::SummaryColumn::Client,
+                crate::SummaryColumn::Total,
+            ])
+            .unwrap();
+        let mut output = Vec::new();
+        accounts.print_to(&mut output).unwrap();
+        assert_eq!(
+            "client,total\n1,2.5\n",
+            std::str::from_utf8(&output).unwrap()
+        );
+        assert_eq!(
+            Err(crate::DuplicatedColumn(crate::SummaryColumn::Total)),
+            accounts.set_columns(vec![
+                crate::SummaryColumn::Total,
+                crate::SummaryColumn::Total
+            ])
+        );
+        assert!("balance".parse::<crate::SummaryColumn>().is_err());
+    }
+
+    #[test]
+    fn transaction_id_scope() {
+        let sample_operation = "type,client,tx,amount
+deposit,1,1,2.0
+deposit,2,1,3.0
+dispute,2,1,";
+        let output = try_input(sample_operation);
+        assert_eq!(
+            "client,available,held,total,locked\n1,2.0,0.0,2.0,false\n",
+            std::str::from_utf8(&output).unwrap()
+        );
+        let config = crate::Config {
+            transaction_id_scope: crate::TransactionIdScope::PerClient,
+            ..Default::default()
+        };
+        let output = try_input_with(sample_operation, &config);
+        assert_eq!(
+            "client,available,held,total,locked\n1,2.0,0.0,2.0,false\n2,0.0,3.0,3.0,false\n",
+            std::str::from_utf8(&output).unwrap()
+        );
+    }
+
+    #[test]
+    fn process_batch() {
+        let lines = vec![
+            crate::TransactionLine::new(
+                crate::TransactionKind::Deposit,
+                crate::ClientId(1),
+                crate::TransactionId(1),
+                Some(crate::Amount::from("5.25")),
+            ),
+            crate::TransactionLine::new(
+                crate::TransactionKind::Withdrawal,
+                crate::ClientId(1),
+                crate::TransactionId(2),
+                Some(crate::Amount::from("1.5")),
+            ),
+            crate::TransactionLine::new(
+                crate::TransactionKind::Dispute,
+                crate::ClientId(1),
+                crate::TransactionId(2),
+                None,
+            ),
+            crate::TransactionLine::new(
+                crate::TransactionKind::Withdrawal,
+                crate::ClientId(2),
+                crate::TransactionId(3),
+                Some(crate::Amount::from("1.0")),
+            ),
+        ];
+        let mut accounts = crate::ClientAccounts::new();
+        let mut operations_register = crate::MoneyOperationsRegister::new();
+        let report = crate::process_batch(
+            lines,
+            &mut accounts,
+            &mut operations_register,
+            &crate::Config::default(),
+        );
+        assert_eq!(4, report.lines);
+        assert_eq!(3, report.processed);
+        assert_eq!(Some(&1), report.errors.get("MissingClient"));
+        assert_eq!(
+            (crate::Amount::from("3.75"), crate::Amount::from("1.5")),
+            accounts.balances(crate::ClientId(1), &crate::Currency::default())
+        );
+    }
+
+    #[test]
+    fn parallel_matches_serial_randomized() {
+        // Small linear congruential generator, so that failures are reproducible
+        let mut seed: u64 = 42;
+        let mut next = move |bound: u64| {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (seed >> 33) % bound
+        };
+        for _ in 0..20 {
+            let mut input = String::from("type,client,tx,amount\n");
+            for tx in 1..=200 {
+                let client = next(8);
+                let line = match next(6) {
+                    0 | 1 => format!("deposit,{},{},{}.{}", client, tx, next(100), next(100)),
+                    2 => format!("withdrawal,{},{},{}.{}", client, tx, next(50), next(100)),
+                    3 => format!("dispute,{},{},", client, 1 + next(tx)),
+                    4 => format!("resolve,{},{},", client, 1 + next(tx)),
+                    _ => format!("chargeback,{},{},", client, 1 + next(tx)),
+                };
+                input.push_str(&line);
+                input.push('\n');
+            }
+            let with_fees = crate::Config {
+                fee_policy: crate::FeePolicy::Percentage(crate::Amount::from("1.5")),
+                fee_collector: crate::ClientId(100),
+                ..crate::Config::default()
+            };
+            for config in &[crate::Config::default(), with_fees] {
+                let serial = try_input_with(&input, config);
+                for threads in 2..=4 {
+                    let (accounts, _) =
+                        crate::process_parallel(std::iter::once(input.as_bytes()), threads, config)
+                            .unwrap();
+                    let mut parallel = Vec::new();
+                    accounts.print_to(&mut parallel).unwrap();
+                    assert_eq!(serial, parallel, "input:\n{}", input);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn no_header() {
+        let sample_operation = "deposit,1,1,2.0
+withdrawal,1,2,0.5";
+        let config = crate::Config {
+            has_headers: false,
+            ..Default::default()
+        };
+        let output = try_input_with(sample_operation, &config);
+        assert_eq!(
+            "client,available,held,total,locked\n1,1.5,0.0,1.5,false\n",
+            std::str::from_utf8(&output).unwrap()
+        );
+    }
+
+    #[test]
+    fn locked_accounts() {
+        let sample_operation = "type,client,tx,amount
+deposit,1,1,2.0
+deposit,2,2,3.0
+deposit,2,3,1.0
+dispute,2,3,
+chargeback,2,3,";
+        let mut accounts = crate::ClientAccounts::new();
+        let mut operations_register = crate::MoneyOperationsRegister::new();
+        crate::read_transactions_file(
+            sample_operation.as_bytes(),
+            &mut accounts,
+            &mut operations_register,
+            &crate::Config::default(),
+            None,
+            None,
+            &crate::NoMetrics,
+        )
+        .unwrap();
+        assert_eq!(
+            vec![(crate::ClientId(2), crate::TransactionId(3))],
+            accounts.locked_accounts()
+        );
+    }
+
+    #[test]
+    fn resolve_unlocks() {
+        let sample_operation = "type,client,tx,amount
+deposit,1,1,10.0
+deposit,1,2,5.0
+dispute,1,1,
+dispute,1,2,
+chargeback,1,1,
+resolve,1,2,";
+        let output = try_input(sample_operation);
+        assert_eq!(
+            "client,available,held,total,locked\n1,0.0,5.0,5.0,true\n",
+            std::str::from_utf8(&output).unwrap()
+        );
+        let config = crate::Config {
+            resolve_unlocks: true,
+            ..Default::default()
+        };
+        let output = try_input_with(sample_operation, &config);
+        assert_eq!(
+            "client,available,held,total,locked\n1,5.0,0.0,5.0,false\n",
+            std::str::from_utf8(&output).unwrap()
+        );
+    }
+
+    #[test]
+    fn row_error() {
+        let sample_operation = "type,client,tx,amount
+deposit,1,1,2.0
+refund,1,2,1.0";
+        let errors = crate::validate_file(sample_operation.as_bytes(), &crate::Config::default());
+        assert_eq!(1, errors.len());
+        assert!(matches!(errors[0], (3, crate::Error::RowError { .. })));
+        assert!(errors[0].1.to_string().ends_with("(row: refund,1,2,1.0)"));
+    }
+
+    #[test]
+    fn locked_allows_deposit() {
+        let sample_operation = "type,client,tx,amount
+deposit,1,1,10.0
+deposit,1,2,5.0
+dispute,1,1,
+chargeback,1,1,
+deposit,1,3,2.0
+withdrawal,1,4,1.0";
+        let output = try_input(sample_operation);
+        assert_eq!(
+            "client,available,held,total,locked\n1,5.0,0.0,5.0,true\n",
+            std::str::from_utf8(&output).unwrap()
+        );
+        let config = crate::Config {
+            locked_allows_deposit: true,
+            ..Default::default()
+        };
+        let output = try_input_with(sample_operation, &config);
+        assert_eq!(
+            "client,available,held,total,locked\n1,7.0,0.0,7.0,true\n",
+            std::str::from_utf8(&output).unwrap()
+        );
+    }
+
+    #[test]
+    fn platform_totals() {
+        let sample_operation = "type,client,tx,amount
+deposit,1,1,10.0
+deposit,2,2,5.5
+deposit,3,3,2.0
+dispute,2,2,
+withdrawal,3,4,0.5
+dispute,1,1,
+chargeback,1,1,";
+        let mut accounts = crate::ClientAccounts::new();
+        let mut operations_register = crate::MoneyOperationsRegister::new();
+        crate::read_transactions_file(
+            sample_operation.as_bytes(),
+            &mut accounts,
+            &mut operations_register,
+            &crate::Config::default(),
+            None,
+            None,
+            &crate::NoMetrics,
+        )
+        .unwrap();
+        let totals = accounts.platform_totals().unwrap();
+        assert_eq!(
+            Some(&crate::PlatformTotals {
+                available: crate::Amount::from("1.5"),
+                held: crate::Amount::from("5.5"),
+                total: crate::Amount::from("7.0"),
+                locked_accounts: 1,
+            }),
+            totals.get(&crate::Currency::default())
+        );
+        assert_eq!(1, totals.len());
+        assert_eq!(
+            "total,1.5,5.5,7.0,1",
+            totals[&crate::Currency::default()].to_string()
+        );
+
+        accounts.set_decimals(0).unwrap();
+        accounts.set_volume_columns(true);
+        let mut footer = Vec::new();
+        accounts.print_totals_to(&totals, &mut footer).unwrap();
+        assert_eq!("total,2,6,7,1,,\n", std::str::from_utf8(&footer).unwrap());
+
+        let accounts = crate::ClientAccounts::from_snapshot(vec![
+            (
+                crate::ClientId(1),
+                crate::Amount::MAX,
+                crate::Amount::ZERO,
+                false,
+            ),
+            (
+                crate::ClientId(2),
+                crate::Amount::MAX,
+                crate::Amount::ZERO,
+                false,
+            ),
+        ]);
+        assert_eq!(Err(crate::TotalsOverflow), accounts.platform_totals());
+    }
+
+    #[test]
+    fn platform_totals_currencies() {
+        let sample_operation = "type,client,tx,amount,currency
+deposit,1,1,10.0,EUR
+deposit,2,2,5.5,USD
+deposit,2,3,2.0,EUR";
+        let mut accounts = crate::ClientAccounts::new();
+        let mut operations_register = crate::MoneyOperationsRegister::new();
+        crate::read_transactions_file(
+            sample_operation.as_bytes(),
+            &mut accounts,
+            &mut operations_register,
+            &crate::Config::default(),
+            None,
+            None,
+            &crate::NoMetrics,
+        )
+        .unwrap();
+        let totals = accounts.platform_totals().unwrap();
+        assert_eq!(2, totals.len());
+        assert_eq!(
+            crate::Amount::from("12.0"),
+            totals[&crate::Currency("EUR".to_string())].total
+        );
+        assert_eq!(
+            crate::Amount::from("5.5"),
+            totals[&crate::Currency("USD".to_string())].total
+        );
+
+        let mut footer = Vec::new();
+        accounts.print_totals_to(&totals, &mut footer).unwrap();
+        assert_eq!(
+            "total,EUR,12.0,0.0,12.0,0\ntotal,USD,5.5,0.0,5.5,0\n",
+            std::str::from_utf8(&footer).unwrap()
+        );
+        let mut footer = Vec::new();
+        accounts.print_totals_json_to(&totals, &mut footer).unwrap();
+        assert_eq!(
+            concat!(
+                r#"{"currency":"EUR","available":12.0,"held":0.0,"total":12.0,"locked_accounts":0}"#,
+                "\n",
+                r#"{"currency":"USD","available":5.5,"held":0.0,"total":5.5,"locked_accounts":0}"#,
+                "\n"
+            ),
+            std::str::from_utf8(&footer).unwrap()
+        );
+    }
+
+    #[test]
+    fn latin1_input() {
+        let sample_operation: &[u8] = b"type,client,tx,amount,comment
+deposit,1,1,2.0,caf\xe9
+withdrawal,1,2,0.5,d\xe9j\xe0 pay\xe9";
+        let mut accounts = crate::ClientAccounts::new();
+        let mut operations_register = crate::MoneyOperationsRegister::new();
+        let report = crate::read_transactions_file(
+            crate::decoding_reader(sample_operation, encoding_rs::WINDOWS_1252),
+            &mut accounts,
+            &mut operations_register,
+            &crate::Config::default(),
+            None,
+            None,
+            &crate::NoMetrics,
+        )
+        .unwrap();
+        assert_eq!(2, report.processed);
+        assert_eq!(
+            (crate::Amount::from("1.5"), crate::Amount::ZERO),
+            accounts.balances(crate::ClientId(1), &crate::Currency::default())
+        );
+    }
+
+    #[test]
+    fn allowed_kinds() {
+        let sample_operation = "type,client,tx,amount
+deposit,1,1,2.0
+withdrawal,1,2,1.0
+deposit,1,3,3.0
+dispute,1,1,
+chargeback,1,1,";
+        let config = crate::Config {
+            allowed_kinds: std::iter::once(crate::TransactionKind::Deposit).collect(),
+            ..Default::default()
+        };
+        let mut accounts = crate::ClientAccounts::new();
+        let mut operations_register = crate::MoneyOperationsRegister::new();
+        let mut errors = Vec::new();
+        let report = crate::read_transactions_file(
+            sample_operation.as_bytes(),
+            &mut accounts,
+            &mut operations_register,
+            &config,
+            None,
+            Some(&mut errors),
+            &crate::NoMetrics,
+        )
+        .unwrap();
+        assert_eq!(2, report.processed);
+        assert_eq!(3, report.skipped);
+        assert_eq!(0, report.rejected());
+        assert!(errors.is_empty());
+        assert_eq!(
+            (crate::Amount::from("5.0"), crate::Amount::ZERO),
+            accounts.balances(crate::ClientId(1), &crate::Currency::default())
+        );
+    }
+
+    #[test]
+    fn error_source() {
+        use std::error::Error;
+
+        let sample_operation: &[u8] = b"type,client,tx,amount
+deposit,1,1,2.\xff
+deposit,1,x,2.0
+withdrawal,1,2,3.0";
+        let errors = crate::validate_file(sample_operation, &crate::Config::default());
+        assert_eq!(3, errors.len());
+        assert!(matches!(errors[0].1, crate::Error::DeserializationError(_)));
+        assert!(errors[0]
+            .1
+            .source()
+            .and_then(|source| source.downcast_ref::<csv::Error>())
+            .is_some());
+        assert!(errors[1].1.source().is_none());
+        assert!(errors[2]
+            .1
+            .source()
+            .and_then(|source| source.downcast_ref::<crate::TransactionError>())
+            .is_some());
+    }
+
+    #[test]
+    fn max_rows() {
+        let sample_operation = "type,client,tx,amount
+deposit,1,1,2.0
+deposit,1,2,3.0
+deposit,1,3,4.0";
+        let config = crate::Config {
+            max_rows: Some(2),
+            ..Default::default()
+        };
+        let mut accounts = crate::ClientAccounts::new();
+        let mut operations_register = crate::MoneyOperationsRegister::new();
+        let report = crate::read_transactions_file(
+            sample_operation.as_bytes(),
+            &mut accounts,
+            &mut operations_register,
+            &config,
+            None,
+            None,
+            &crate::NoMetrics,
+        )
+        .unwrap();
+        assert_eq!(2, report.processed);
+        assert_eq!(Some(&1), report.errors.get("RowLimitExceeded"));
+        assert_eq!(
+            (crate::Amount::from("5.0"), crate::Amount::ZERO),
+            accounts.balances(crate::ClientId(1), &crate::Currency::default())
+        );
+    }
+
+    #[test]
+    fn resolve_noop_if_not_disputed() {
+        let sample_operation = "type,client,tx,amount
+deposit,1,1,2.0
+resolve,1,1,
+dispute,1,1,
+resolve,1,1,
+resolve,1,1,";
+        let errors = crate::validate_file(sample_operation.as_bytes(), &crate::Config::default());
+        assert!(matches!(
+            errors.as_slice(),
+            [
+                (
+                    3,
+                    crate::Error::TransactionError(crate::TransactionError::WrongTransactionState)
+                ),
+                (
+                    6,
+                    crate::Error::TransactionError(crate::TransactionError::DuplicateClaim(_))
+                ),
+            ]
+        ));
+        let config = crate::Config {
+            resolve_noop_if_not_disputed: true,
+            ..Default::default()
+        };
+        assert!(crate::validate_file(sample_operation.as_bytes(), &config).is_empty());
+        let output = try_input_with(sample_operation, &config);
+        assert_eq!(
+            "client,available,held,total,locked\n1,2.0,0.0,2.0,false\n",
+            std::str::from_utf8(&output).unwrap()
+        );
+    }
+
+    #[test]
+    fn parse_amount() {
+        use crate::{parse_amount, Amount, Error, TransactionKind};
+
+        assert!(matches!(
+            parse_amount(None, TransactionKind::Deposit),
+            Err(Error::MissingAmount)
+        ));
+        assert!(matches!(
+            parse_amount(Some("-1.0"), TransactionKind::Withdrawal),
+            Err(Error::WrongArgument)
+        ));
+        assert!(matches!(
+            parse_amount(Some("0"), TransactionKind::Deposit),
+            Ok(Some(Amount::ZERO))
+        ));
+        assert_eq!(
+            Some(Amount::from("1.5")),
+            parse_amount(Some("1.5"), TransactionKind::Deposit).unwrap()
+        );
+        assert!(matches!(
+            parse_amount(Some("1.5"), TransactionKind::Chargeback),
+            Err(Error::WrongArgument)
+        ));
+        assert!(matches!(
+            parse_amount(None, TransactionKind::Dispute),
+            Ok(None)
+        ));
+        assert!(matches!(
+            parse_amount(Some("abc"), TransactionKind::Deposit),
+            Err(Error::InvalidAmount(_))
+        ));
+    }
+
+    #[test]
+    fn decimal_separator() {
+        let sample_operation = "type;client;tx;amount
+deposit;1;1;2,5";
+        let config = crate::Config {
+            delimiter: b';',
+            decimal_separator: ',',
+            ..Default::default()
+        };
+        let output = try_input_with(sample_operation, &config);
+        assert_eq!(
+            "client,available,held,total,locked\n1,2.5,0.0,2.5,false\n",
+            std::str::from_utf8(&output).unwrap()
+        );
+    }
+
+    #[test]
+    fn resolves_release_their_own_amount() {
+        use crate::{Amount, ClientId, TransactionId, TransactionKind, TransactionLine};
+
+        let line = |kind, tx, amount: Option<&str>| {
+            TransactionLine::new(
+                kind,
+                ClientId(1),
+                TransactionId(tx),
+                amount.map(Amount::from),
+            )
+        };
+        let mut accounts = crate::ClientAccounts::new();
+        let mut operations_register = crate::MoneyOperationsRegister::new();
+        let config = crate::Config::default();
+        let mut process = |lines| {
+            crate::process_batch(lines, &mut accounts, &mut operations_register, &config);
+            accounts.balances(ClientId(1), &crate::Currency::default())
+        };
+        assert_eq!(
+            (Amount::from("15"), Amount::from("45")),
+            process(vec![
+                line(TransactionKind::Deposit, 1, Some("10")),
+                line(TransactionKind::Deposit, 2, Some("20")),
+                line(TransactionKind::Deposit, 3, Some("30")),
+                line(TransactionKind::Dispute, 1, None),
+                line(TransactionKind::Dispute, 2, Some("5")),
+                line(TransactionKind::Dispute, 3, None),
+            ])
+        );
+        assert_eq!(
+            (Amount::from("20"), Amount::from("40")),
+            process(vec![line(TransactionKind::Resolve, 2, None)])
+        );
+        assert_eq!(
+            (Amount::from("50"), Amount::from("10")),
+            process(vec![line(TransactionKind::Resolve, 3, None)])
+        );
+        assert_eq!(
+            (Amount::from("60"), Amount::ZERO),
+            process(vec![line(TransactionKind::Resolve, 1, None)])
+        );
+    }
+
+    #[test]
+    fn dropped_post_lock() {
+        let sample_operation = "type,client,tx,amount
+deposit,1,1,10.0
+deposit,1,2,5.0
+deposit,2,3,1.0
+dispute,1,1,
+chargeback,1,1,
+dispute,1,2,
+dispute,1,2,
+dispute,2,3,";
+        let mut accounts = crate::ClientAccounts::new();
+        let mut operations_register = crate::MoneyOperationsRegister::new();
+        let report = crate::read_transactions_file(
+            sample_operation.as_bytes(),
+            &mut accounts,
+            &mut operations_register,
+            &crate::Config::default(),
+            None,
+            None,
+            &crate::NoMetrics,
+        )
+        .unwrap();
+        assert_eq!(
+            std::iter::once((crate::ClientId(1), 2)).collect::<std::collections::BTreeMap<_, _>>(),
+            report.dropped_post_lock
+        );
+    }
+
+    /// Fails once `writes` writes succeeded
+    struct FailingWriter {
+        writes: usize,
+    }
+
+    impl std::io::Write for FailingWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if self.writes == 0 {
+                return Err(std::io::ErrorKind::BrokenPipe.into());
+            }
+            self.writes -= 1;
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn output_errors_propagate() {
+        let mut accounts = crate::ClientAccounts::new();
+        accounts.create_client(crate::ClientId(1), crate::Amount::from("1.0"));
+        let error = accounts
+            .print_to(&mut FailingWriter { writes: 0 })
+            .unwrap_err();
+        assert!(error.is_io_error());
+        let error = accounts
+            .print_json_to(&mut FailingWriter { writes: 0 })
+            .unwrap_err();
+        assert_eq!(std::io::ErrorKind::BrokenPipe, error.kind());
+        assert!(accounts.print_to(&mut FailingWriter { writes: 1 }).is_ok());
+    }
+
+    #[test]
+    fn print_client() {
+        let mut accounts = crate::ClientAccounts::new();
+        let mut operations_register = crate::MoneyOperationsRegister::new();
+        accounts.create_client(crate::ClientId(2), crate::Amount::from("1.0"));
+        crate::process_batch(
+            vec![crate::TransactionLine::new(
+                crate::TransactionKind::Deposit,
+                crate::ClientId(1),
+                crate::TransactionId(1),
+                Some(crate::Amount::from("2.5")),
+            )],
+            &mut accounts,
+            &mut operations_register,
+            &crate::Config::default(),
+        );
+        let mut output = Vec::new();
+        accounts
+            .print_client_to(crate::ClientId(1), &mut output)
+            .unwrap();
+        assert_eq!(
+            "1,2.5,0.0,2.5,false\n",
+            std::str::from_utf8(&output).unwrap()
+        );
+    }
+
+    #[test]
+    fn skip_unknown_kinds() {
+        let sample_operation = "type,client,tx,amount
+deposit,1,1,2.0
+foo,1,2,1.0";
+        let config = crate::Config {
+            skip_unknown_kinds: true,
+            ..Default::default()
+        };
+        let mut accounts = crate::ClientAccounts::new();
+        let mut operations_register = crate::MoneyOperationsRegister::new();
+        let report = crate::read_transactions_file(
+            sample_operation.as_bytes(),
+            &mut accounts,
+            &mut operations_register,
+            &config,
+            None,
+            None,
+            &crate::NoMetrics,
+        )
+        .unwrap();
+        assert_eq!(1, report.processed);
+        assert_eq!(1, report.skipped);
+        assert_eq!(0, report.rejected());
+        let errors = crate::validate_file(sample_operation.as_bytes(), &crate::Config::default());
+        assert!(matches!(errors[..], [(3, crate::Error::RowError { .. })]));
+    }
+
+    #[test]
+    fn all_or_nothing() {
+        let mut accounts = crate::ClientAccounts::new();
+        let mut operations_register = crate::MoneyOperationsRegister::new();
+        let config = crate::Config::default();
+        let report = crate::process_all_or_nothing(
+            "type,client,tx,amount\ndeposit,1,1,2.0".as_bytes(),
+            &mut accounts,
+            &mut operations_register,
+            &config,
+        )
+        .unwrap();
+        assert_eq!(1, report.processed);
+
+        let sample_operation = "type,client,tx,amount
+deposit,1,2,3.0
+withdrawal,1,3,10.0
+deposit,2,4,1.0";
+        let result = crate::process_all_or_nothing(
+            sample_operation.as_bytes(),
+            &mut accounts,
+            &mut operations_register,
+            &config,
+        );
+        assert!(matches!(
+            result,
+            Err((
+                3,
+                crate::Error::TransactionError(crate::TransactionError::NotEnoughFunds)
+            ))
+        ));
+        assert!(!operations_register.contains(crate::ClientId(1), crate::TransactionId(2)));
+        assert_eq!(
+            (crate::Amount::from("2.0"), crate::Amount::ZERO),
+            accounts.balances(crate::ClientId(1), &crate::Currency::default())
+        );
+        let mut buf = Vec::new();
+        accounts.print_to(&mut buf).unwrap();
+        assert_eq!(
+            "client,available,held,total,locked\n1,2.0,0.0,2.0,false\n",
+            String::from_utf8(buf).unwrap()
+        );
+    }
+
+    #[test]
+    fn held_entries() {
+        let sample_operation = "type,client,tx,amount
+deposit,1,1,2.0
+deposit,1,2,3.0
+dispute,1,1,
+dispute,1,2,";
+        let mut accounts = crate::ClientAccounts::new();
+        let mut operations_register = crate::MoneyOperationsRegister::new();
+        let config = crate::Config::default();
+        crate::read_transactions_file(
+            sample_operation.as_bytes(),
+            &mut accounts,
+            &mut operations_register,
+            &config,
+            None,
+            None,
+            &crate::NoMetrics,
+        )
+        .unwrap();
+        assert_eq!(
+            &[
+                (crate::TransactionId(1), crate::Amount::from("2.0")),
+                (crate::TransactionId(2), crate::Amount::from("3.0"))
+            ],
+            accounts
+                .get_account(crate::ClientId(1))
+                .unwrap()
+                .held_entries()
+        );
+        crate::read_transactions_file(
+            "type,client,tx,amount\nresolve,1,1,".as_bytes(),
+            &mut accounts,
+            &mut operations_register,
+            &config,
+            None,
+            None,
+            &crate::NoMetrics,
+        )
+        .unwrap();
+        let client = accounts.get_account(crate::ClientId(1)).unwrap();
+        assert_eq!(
+            &[(crate::TransactionId(2), crate::Amount::from("3.0"))],
+            client.held_entries()
+        );
+        assert_eq!(crate::Amount::from("3.0"), client.held());
+    }
+
+    #[test]
+    fn authorize_capture() {
+        let sample_operation = "type,client,tx,amount
+deposit,1,1,10.0
+authorize,1,2,4.0
+capture,1,2,
+void,1,2,";
+        assert_eq!(
+            "client,available,held,total,locked\n1,6.0,0.0,6.0,false\n",
+            std::str::from_utf8(&try_input(sample_operation)).unwrap()
+        );
+    }
+
+    #[test]
+    fn authorize_void() {
+        let sample_operation = "type,client,tx,amount
+deposit,1,1,10.0
+authorize,1,2,4.0
+void,1,2,
+capture,1,2,
+authorize,1,3,11.0";
+        assert_eq!(
+            "client,available,held,total,locked\n1,10.0,0.0,10.0,false\n",
+            std::str::from_utf8(&try_input(sample_operation)).unwrap()
+        );
+        let sample_operation = "type,client,tx,amount
+deposit,1,1,10.0
+authorize,1,2,4.0
+dispute,1,2,";
+        assert_eq!(
+            "client,available,held,total,locked\n1,6.0,4.0,10.0,false\n",
+            std::str::from_utf8(&try_input(sample_operation)).unwrap()
+        );
+        // A locked account can't capture, but can still void to get its funds back
+        let sample_operation = "type,client,tx,amount
+deposit,1,1,10.0
+deposit,1,2,5.0
+authorize,1,3,4.0
+dispute,1,2,
+chargeback,1,2,
+capture,1,3,
+void,1,3,";
+        assert_eq!(
+            "client,available,held,total,locked\n1,10.0,0.0,10.0,true\n",
+            std::str::from_utf8(&try_input(sample_operation)).unwrap()
+        );
+    }
+
+    #[test]
+    fn diff_summaries() {
+        let old = crate::ClientAccounts::from_snapshot(vec![
+            (
+                crate::ClientId(1),
+                crate::Amount::from("2.0"),
+                crate::Amount::ZERO,
+                false,
+            ),
+            (
+                crate::ClientId(3),
+                crate::Amount::from("1.0"),
+                crate::Amount::ZERO,
+                false,
+            ),
+        ]);
+        let mut buf = Vec::new();
+        old.print_to(&mut buf).unwrap();
+        let old = crate::ClientAccounts::read_summaries(buf.as_slice()).unwrap();
+        let new = crate::ClientAccounts::from_snapshot(vec![
+            (
+                crate::ClientId(1),
+                crate::Amount::from("5.0"),
+                crate::Amount::from("1.0"),
+                true,
+            ),
+            (
+                crate::ClientId(2),
+                crate::Amount::from("1.5"),
+                crate::Amount::ZERO,
+                false,
+            ),
+            (
+                crate::ClientId(3),
+                crate::Amount::from("1.0"),
+                crate::Amount::ZERO,
+                false,
+            ),
+        ]);
+        assert_eq!(
+            vec![
+                crate::BalanceDelta {
+                    client: crate::ClientId(1),
+                    currency: crate::Currency::default(),
+                    change: crate::DeltaKind::Changed,
+                    available: crate::Amount::from("3.0"),
+                    held: crate::Amount::from("1.0"),
+                    locked: Some(true),
+                },
+                crate::BalanceDelta {
+                    client: crate::ClientId(2),
+                    currency: crate::Currency::default(),
+                    change: crate::DeltaKind::Appeared,
+                    available: crate::Amount::from("1.5"),
+                    held: crate::Amount::ZERO,
+                    locked: None,
+                },
+            ],
+            crate::diff_summaries(&old, &new).unwrap()
+        );
+        assert_eq!(
+            crate::DeltaKind::Disappeared,
+            crate::diff_summaries(&new, &old).unwrap()[1].change
+        );
+        let overdrawn = crate::ClientAccounts::from_snapshot(vec![(
+            crate::ClientId(1),
+            crate::Amount::from("-1.0"),
+            crate::Amount::ZERO,
+            false,
+        )]);
+        let full = crate::ClientAccounts::from_snapshot(vec![(
+            crate::ClientId(1),
+            crate::Amount::MAX,
+            crate::Amount::ZERO,
+            false,
+        )]);
+        assert_eq!(
+            Err(crate::DeltaOverflow(crate::ClientId(1))),
+            crate::diff_summaries(&full, &overdrawn)
+        );
+    }
+
+    #[test]
+    fn redispute_allowed() {
+        let sample_operation = "type,client,tx,amount
+deposit,1,1,2.0
+dispute,1,1,
+resolve,1,1,
+dispute,1,1,";
+        assert_eq!(
+            "client,available,held,total,locked\n1,0.0,2.0,2.0,false\n",
+            std::str::from_utf8(&try_input(sample_operation)).unwrap()
+        );
+        let config = crate::Config {
+            redispute_allowed: false,
+            ..crate::Config::default()
+        };
+        let mut accounts = crate::ClientAccounts::new();
+        let mut operations_register = crate::MoneyOperationsRegister::new();
+        let results: Vec<_> = crate::transaction_results(
+            sample_operation.as_bytes(),
+            &mut accounts,
+            &mut operations_register,
+            &config,
+        )
+        .collect();
+        assert!(matches!(
+            results[3],
+            Err(crate::Error::TransactionError(
+                crate::TransactionError::AlreadyResolved(crate::TransactionId(1))
+            ))
+        ));
+        assert_eq!(
+            (crate::Amount::from("2.0"), crate::Amount::ZERO),
+            accounts.balances(crate::ClientId(1), &crate::Currency::default())
+        );
+    }
+
+    #[test]
+    fn strict() {
+        let sample_operation = "type,client,tx,amount
+deposit,x,1,2.0
+deposit,1,2,2.0";
+        let config = crate::Config {
+            strict: true,
+            ..crate::Config::default()
+        };
+        let mut accounts = crate::ClientAccounts::new();
+        let mut operations_register = crate::MoneyOperationsRegister::new();
+        let result = crate::read_transactions_file(
+            sample_operation.as_bytes(),
+            &mut accounts,
+            &mut operations_register,
+            &config,
+            None,
+            None,
+            &crate::NoMetrics,
+        );
+        assert!(matches!(result, Err(crate::Error::InvalidId { .. })));
+        assert!(accounts.balance_of(crate::ClientId(1)).is_none());
+
+        let report = crate::read_transactions_file(
+            sample_operation.as_bytes(),
+            &mut accounts,
+            &mut operations_register,
+            &crate::Config::default(),
+            None,
+            None,
+            &crate::NoMetrics,
+        )
+        .unwrap();
+        assert_eq!(1, report.processed);
+        assert_eq!(1, report.rejected());
+    }
+
+    #[test]
+    fn exact_balances() {
+        let mut accounts = crate::ClientAccounts::new();
+        let mut operations_register = crate::MoneyOperationsRegister::new();
+        let config = crate::Config::default();
+        let deposits = "type,client,tx,amount
+deposit,1,1,0.1
+deposit,1,2,0.1
+deposit,1,3,0.1";
+        crate::transaction_results(
+            deposits.as_bytes(),
+            &mut accounts,
+            &mut operations_register,
+            &config,
+        )
+        .for_each(|result| assert!(result.is_ok()));
+        assert_eq!(
+            (crate::Amount::from("0.3"), crate::Amount::ZERO),
+            accounts.balances(crate::ClientId(1), &crate::Currency::default())
+        );
+        let withdrawal = "type,client,tx,amount\nwithdrawal,1,4,0.3";
+        crate::transaction_results(
+            withdrawal.as_bytes(),
+            &mut accounts,
+            &mut operations_register,
+            &config,
+        )
+        .for_each(|result| assert!(result.is_ok()));
+        assert_eq!(
+            (crate::Amount::ZERO, crate::Amount::ZERO),
+            accounts.balances(crate::ClientId(1), &crate::Currency::default())
+        );
+    }
+
+    #[test]
+    fn parse_line() {
+        let line = crate::parse_line("deposit, 1, 2, 1.5").unwrap();
+        assert_eq!(crate::TransactionKind::Deposit, line.transaction_type());
+        assert_eq!(crate::ClientId(1), line.client_id());
+        assert_eq!(crate::TransactionId(2), line.transaction_id());
+        assert_eq!(Some("1.5"), line.amount());
+        assert!(matches!(
+            crate::parse_line("refund,1,2,1.5"),
+            Err(crate::Error::RowError { row, .. }) if row == "refund,1,2,1.5"
+        ));
+    }
+
+    #[test]
+    fn metadata() {
+        let sample_operation = "type,client,tx,amount,region
+deposit,1,1,2.0,emea
+deposit,2,2,1.0,
+deposit,1,3,1.0,apac";
+        assert_eq!(
+            "client,available,held,total,locked,region\n1,3.0,0.0,3.0,false,emea\n2,1.0,0.0,1.0,false,\n",
+            std::str::from_utf8(&try_input(sample_operation)).unwrap()
+        );
+        // Rejected lines don't tag the account
+        let sample_operation = "type,client,tx,amount,region
+deposit,1,1,2.0,
+withdrawal,1,2,5.0,emea
+deposit,1,3,1.0,apac";
+        assert_eq!(
+            "client,available,held,total,locked,region\n1,3.0,0.0,3.0,false,apac\n",
+            std::str::from_utf8(&try_input(sample_operation)).unwrap()
+        );
+        // The rows of a single client have the same columns
+        let sample_operation = "type,client,tx,amount,region
+deposit,1,1,2.0,emea
+deposit,2,2,1.0,";
+        let mut accounts = crate::ClientAccounts::new();
+        let mut operations_register = crate::MoneyOperationsRegister::new();
+        crate::read_transactions_file(
+            sample_operation.as_bytes(),
+            &mut accounts,
+            &mut operations_register,
+            &crate::Config::default(),
+            None,
+            None,
+            &crate::NoMetrics,
+        )
+        .unwrap();
+        let mut output = Vec::new();
+        accounts
+            .print_client_to(crate::ClientId(1), &mut output)
+            .unwrap();
+        accounts
+            .print_client_to(crate::ClientId(2), &mut output)
+            .unwrap();
+        assert_eq!(
+            "1,2.0,0.0,2.0,false,emea\n2,1.0,0.0,1.0,false,\n",
+            std::str::from_utf8(&output).unwrap()
+        );
+    }
+
+    #[test]
+    fn min_available() {
+        let sample_operation = "type,client,tx,amount
+deposit,1,1,1000.0
+deposit,1,2,200.0
+withdrawal,1,3,1100.0
+dispute,1,1,
+dispute,1,2,";
+        let config = crate::Config {
+            min_available: Some(crate::Amount::from("-500")),
+            ..crate::Config::default()
+        };
+        let mut accounts = crate::ClientAccounts::new();
+        let mut operations_register = crate::MoneyOperationsRegister::new();
+        let results: Vec<_> = crate::transaction_results(
+            sample_operation.as_bytes(),
+            &mut accounts,
+            &mut operations_register,
+            &config,
+        )
+        .collect();
+        assert!(matches!(
+            results[3],
+            Err(crate::Error::TransactionError(
+                crate::TransactionError::WouldExceedExposure(crate::ClientId(1))
+            ))
+        ));
+        assert!(matches!(results[4], Ok(())));
+        assert_eq!(
+            (crate::Amount::from("-100.0"), crate::Amount::from("200.0")),
+            accounts.balances(crate::ClientId(1), &crate::Currency::default())
+        );
+    }
+
+    #[test]
+    fn read_transactions_binary() {
+        let mut input = Vec::new();
+        input.extend_from_slice(&crate::binary::encode_record(
+            crate::TransactionKind::Deposit,
+            crate::ClientId(1),
+            crate::TransactionId(1),
+            Some(crate::Amount::from("2.5")),
+        ));
+        input.extend_from_slice(&crate::binary::encode_record(
+            crate::TransactionKind::Withdrawal,
+            crate::ClientId(1),
+            crate::TransactionId(2),
+            Some(crate::Amount::from("1.0")),
+        ));
+        let mut accounts = crate::ClientAccounts::new();
+        let mut operations_register = crate::MoneyOperationsRegister::new();
+        let report = crate::read_transactions_binary(
+            input.as_slice(),
+            &mut accounts,
+            &mut operations_register,
+            &crate::Config::default(),
+        )
+        .unwrap();
+        assert_eq!(2, report.processed);
+        let mut buf = Vec::new();
+        accounts.print_to(&mut buf).unwrap();
+        assert_eq!(
+            try_input("type,client,tx,amount\ndeposit,1,1,2.5\nwithdrawal,1,2,1.0"),
+            buf
+        );
+
+        input.truncate(crate::binary::RECORD_LEN + 3);
+        assert!(crate::read_transactions_binary(
+            input.as_slice(),
+            &mut crate::ClientAccounts::new(),
+            &mut crate::MoneyOperationsRegister::new(),
+            &crate::Config::default(),
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn blank_lines() {
+        let sample_operation = "type,client,tx,amount
+deposit,1,1,2.0
+
+deposit,1,2,1.0
+   \t
+withdrawal,1,3,0.5
+ , ,";
+        let mut accounts = crate::ClientAccounts::new();
+        let mut operations_register = crate::MoneyOperationsRegister::new();
+        let report = crate::read_transactions_file(
+            sample_operation.as_bytes(),
+            &mut accounts,
+            &mut operations_register,
+            &crate::Config::default(),
+            None,
+            None,
+            &crate::NoMetrics,
+        )
+        .unwrap();
+        assert_eq!(3, report.processed);
+        assert_eq!(2, report.blank);
+        assert_eq!(0, report.rejected());
+        assert_eq!(
+            (crate::Amount::from("2.5"), crate::Amount::ZERO),
+            accounts.balances(crate::ClientId(1), &crate::Currency::default())
+        );
+    }
+
+    #[test]
+    fn reuse_charged_back_ids() {
+        let sample_operation = "type,client,tx,amount
+deposit,1,1,10.0
+deposit,1,2,5.0
+dispute,1,2,
+chargeback,1,2,
+unlock,1,0,
+deposit,1,2,3.0";
+        assert_eq!(
+            "client,available,held,total,locked\n1,10.0,0.0,10.0,false\n",
+            std::str::from_utf8(&try_input(sample_operation)).unwrap()
+        );
+        let config = crate::Config {
+            reuse_charged_back_ids: true,
+            ..crate::Config::default()
+        };
+        assert_eq!(
+            "client,available,held,total,locked\n1,13.0,0.0,13.0,false\n",
+            std::str::from_utf8(&try_input_with(sample_operation, &config)).unwrap()
+        );
+    }
+
+    #[test]
+    fn exact_held_funds() {
+        let sample_operation = "type,client,tx,amount
+deposit,1,1,0.1
+deposit,1,2,0.2
+deposit,1,3,1.0003
+dispute,1,1,
+dispute,1,2,
+dispute,1,3,
+resolve,1,2,
+withdrawal,1,4,0.0001
+resolve,1,3,
+chargeback,1,1,";
+        assert_eq!(
+            "client,available,held,total,locked\n1,1.2002,0.0,1.2002,true\n",
+            std::str::from_utf8(&try_input(sample_operation)).unwrap()
+        );
+    }
+
+    #[test]
+    fn parallel_fees() {
+        let sample_operation = "type,client,tx,amount
+deposit,1,1,10.0
+deposit,2,2,10.0
+deposit,3,3,10.0
+withdrawal,1,4,1.0
+withdrawal,2,5,2.0
+withdrawal,3,6,3.0
+withdrawal,1,7,1.0";
+        let config = crate::Config {
+            fee_policy: crate::FeePolicy::Flat(crate::Amount::from("0.1")),
+            fee_collector: crate::ClientId(4),
+            ..crate::Config::default()
+        };
+        let serial = try_input_with(sample_operation, &config);
+        assert!(std::str::from_utf8(&serial)
+            .unwrap()
+            .ends_with("\n4,0.4,0.0,0.4,false\n"));
+        let (accounts, report) =
+            crate::process_parallel(std::iter::once(sample_operation.as_bytes()), 3, &config)
+                .unwrap();
+        assert_eq!(0, report.rejected());
+        let mut parallel = Vec::new();
+        accounts.print_to(&mut parallel).unwrap();
+        assert_eq!(
+            std::str::from_utf8(&serial).unwrap(),
+            std::str::from_utf8(&parallel).unwrap()
+        );
+    }
+
+    #[test]
+    fn parallel_fee_collector_transactions() {
+        let sample_operation = "type,client,tx,amount
+deposit,1,1,10.0
+withdrawal,1,2,1.0
+withdrawal,4,3,0.1";
+        let config = crate::Config {
+            fee_policy: crate::FeePolicy::Flat(crate::Amount::from("0.1")),
+            fee_collector: crate::ClientId(4),
+            ..crate::Config::default()
+        };
+        assert!(matches!(
+            crate::process_parallel(std::iter::once(sample_operation.as_bytes()), 3, &config),
+            Err(crate::Error::ParallelFeeCollector(crate::ClientId(4)))
+        ));
+        // Without fees the collector is a client like the others
+        assert!(crate::process_parallel(
+            std::iter::once(sample_operation.as_bytes()),
+            3,
+            &crate::Config::default()
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn merge_overflow() {
+        let snapshot = || {
+            crate::ClientAccounts::from_snapshot(vec![(
+                crate::ClientId(1),
+                crate::Amount::MAX,
+                crate::Amount::ZERO,
+                false,
+            )])
+        };
+        let mut accounts = snapshot();
+        assert!(matches!(
+            accounts.merge(snapshot()),
+            Err(crate::TransactionError::Overflow(crate::ClientId(1)))
+        ));
+        assert_eq!(
+            crate::Amount::MAX,
+            accounts.balance_of(crate::ClientId(1)).unwrap().available
+        );
+    }
+
+    #[test]
+    fn error_output_failure() {
+        let mut accounts = crate::ClientAccounts::new();
+        let mut operations_register = crate::MoneyOperationsRegister::new();
+        let result = crate::read_transactions_file(
+            "type,client,tx,amount\nwithdrawal,1,1,5.0".as_bytes(),
+            &mut accounts,
+            &mut operations_register,
+            &crate::Config::default(),
+            None,
+            Some(&mut FailingWriter { writes: 0 }),
+            &crate::NoMetrics,
+        );
+        assert!(matches!(result, Err(crate::Error::ErrorOutput(_))));
+    }
+
+    #[test]
+    fn open_authorizations_are_not_evicted() {
+        let sample_operation = "type,client,tx,amount
+deposit,1,1,10.0
+authorize,1,2,1.0
+authorize,1,3,2.0
+authorize,1,4,3.0
+deposit,1,5,1.0
+capture,1,2,
+void,1,3,
+capture,1,4,";
+        let config = crate::Config {
+            register_capacity: Some(2),
+            ..crate::Config::default()
+        };
+        assert_eq!(
+            "client,available,held,total,locked\n1,7.0,0.0,7.0,false\n",
+            std::str::from_utf8(&try_input_with(sample_operation, &config)).unwrap()
+        );
     }
 }
@@ -1,4 +1,14 @@
-use clap::Parser;
+use {
+    clap::{ArgEnum, Parser},
+    std::convert::TryFrom,
+};
+
+#[derive(ArgEnum, Clone, Debug)]
+enum Format {
+    Csv,
+    Json,
+    JsonArray,
+}
 
 /// Simple program to greet a person
 #[derive(Parser, Debug)]
@@ -6,23 +16,354 @@ use clap::Parser;
 struct Args {
     // This is an optional argument that can be written in short (-d)
     // or long form (--debug)
+    /// Log every transaction, RUST_LOG takes precedence
     #[clap(short, long)]
     debug: bool,
-    file_path: std::path::PathBuf,
+    /// Log nothing, even with RUST_LOG, errors stopping the program are still printed
+    #[clap(short, long, conflicts_with_all = &["debug", "report-anomalies"])]
+    quiet: bool,
+    /// Output format of the account summary
+    #[clap(long, arg_enum, default_value = "csv")]
+    format: Format,
+    /// Rounding of the printed amounts: half-up, half-even or truncate
+    #[clap(long, default_value = "half-up")]
+    rounding: payment_engine::RoundingMode,
+    /// Decimal places of the printed amounts, at most the 4 kept by the engine
+    #[clap(long, default_value = "4", possible_values = &["0", "1", "2", "3", "4"])]
+    decimals: usize,
+    /// Add the lifetime deposited and withdrawn amounts to the output
+    #[clap(long)]
+    volume: bool,
+    /// Append the platform totals and the number of locked accounts of each currency to the output
+    #[clap(long)]
+    summary: bool,
+    /// Comma separated columns of the CSV output, in order
+    #[clap(long, use_value_delimiter = true)]
+    columns: Vec<payment_engine::SummaryColumn>,
+    /// Decompress the input with gzip, implied by a .gz extension
+    #[clap(long)]
+    gzip: bool,
+    /// Encoding of the input, e.g. latin1 or windows-1252, UTF-8 by default
+    #[clap(long)]
+    encoding: Option<String>,
+    /// Number of worker threads, clients are split between them. When fees are charged,
+    /// the fee collector must not have transactions of its own
+    #[clap(long)]
+    threads: Option<usize>,
+    /// Maximum number of disputes on a single transaction
+    #[clap(long)]
+    max_disputes: Option<u32>,
+    /// Highest accepted client id
+    #[clap(long)]
+    max_client_id: Option<u32>,
+    /// Field delimiter of the input file
+    #[clap(long, default_value = ",")]
+    delimiter: char,
+    /// Decimal separator of the input amounts
+    #[clap(long, default_value = ".")]
+    decimal_separator: char,
+    /// Stop at the first line that can't be read
+    #[clap(long)]
+    strict: bool,
+    /// Reject files whose header isn't exactly type,client,tx,amount, in any order
+    #[clap(long)]
+    strict_headers: bool,
+    /// The input has no header, columns are type,client,tx,amount in this order
+    #[clap(long, conflicts_with = "strict-headers")]
+    no_header: bool,
+    /// Comma separated ids of the clients allowed to overdraw their account
+    #[clap(long, use_value_delimiter = true)]
+    allow_overdraft: Vec<u32>,
+    /// Treat a negative deposit as a withdrawal, and vice versa
+    #[clap(long)]
+    treat_negative_as_reversal: bool,
+    /// Accept deposits and withdrawals of a zero amount
+    #[clap(long)]
+    allow_zero_amounts: bool,
+    /// Number of past operations kept for disputes, older ones are forgotten
+    #[clap(long)]
+    register_capacity: Option<usize>,
+    /// Comma separated kinds of operations that can be disputed
+    #[clap(long, use_value_delimiter = true, default_value = "deposit,withdrawal")]
+    disputable_kinds: Vec<payment_engine::TransactionKind>,
+    /// Flat fee charged on every withdrawal
+    #[clap(long, conflicts_with = "withdrawal-fee-percent")]
+    withdrawal_fee: Option<payment_engine::Amount>,
+    /// Fee charged on every withdrawal, as a percentage of the amount
+    #[clap(long)]
+    withdrawal_fee_percent: Option<payment_engine::Amount>,
+    /// Client credited with the withdrawal fees
+    #[clap(long, default_value = "0")]
+    fee_collector: u32,
+    /// Disputes of funds already withdrawn: allow-negative or reject-if-negative
+    #[clap(long, default_value = "allow-negative")]
+    dispute_policy: payment_engine::DisputePolicy,
+    /// Lowest available funds a dispute may leave, e.g. -1000
+    #[clap(long, allow_hyphen_values = true)]
+    min_available: Option<payment_engine::Amount>,
+    /// Maximum number of deposits and withdrawals per client
+    #[clap(long)]
+    max_operations_per_client: Option<u32>,
+    /// Uniqueness of the transaction ids: global or per-client
+    #[clap(long, default_value = "global")]
+    transaction_id_scope: payment_engine::TransactionIdScope,
+    /// Accept a resolve on an account locked by a chargeback, and unlock it
+    #[clap(long)]
+    resolve_unlocks: bool,
+    /// Comma separated kinds of transactions to process, the other ones are skipped
+    #[clap(
+        long,
+        use_value_delimiter = true,
+        default_value = "deposit,withdrawal,dispute,resolve,chargeback,unlock,close,accrue,authorize,capture,void"
+    )]
+    allowed_kinds: Vec<payment_engine::TransactionKind>,
+    /// Skip lines of an unknown type with a warning instead of rejecting them
+    #[clap(long)]
+    skip_unknown_kinds: bool,
+    /// Comma separated kinds of transactions to skip
+    #[clap(long, use_value_delimiter = true)]
+    skipped_kinds: Vec<payment_engine::TransactionKind>,
+    /// Maximum number of rows read from each file, the remaining ones are ignored
+    #[clap(long)]
+    max_rows: Option<usize>,
+    /// Accept a resolve of a transaction that isn't disputed, without effect
+    #[clap(long)]
+    resolve_noop_if_not_disputed: bool,
+    /// per-line drops the rejected lines, all-or-nothing aborts the run on the first one
+    #[clap(long, default_value = "per-line", conflicts_with_all = &["threads", "audit-log"])]
+    transaction_mode: payment_engine::TransactionMode,
+    /// Accept new transactions with the id of a charged back one
+    #[clap(long)]
+    reuse_charged_back_ids: bool,
+    /// Reject disputes of a transaction whose previous dispute was resolved
+    #[clap(long)]
+    no_redispute: bool,
+    /// Accept deposits on locked accounts
+    #[clap(long)]
+    locked_allows_deposit: bool,
+    /// Write an audit log of every processed transaction to this path
+    #[clap(long, conflicts_with = "threads")]
+    audit_log: Option<std::path::PathBuf>,
+    /// Print the clients with a negative balance to stderr
+    #[clap(long)]
+    report_anomalies: bool,
+    /// Print the changes from the CSV output of a previous run instead of the accounts
+    #[clap(long)]
+    diff_against: Option<std::path::PathBuf>,
+    /// Only validate the files, printing the rejected lines
+    #[clap(long)]
+    check: bool,
+    /// Files processed one after the other, in the given order
+    #[clap(required = true)]
+    file_paths: Vec<std::path::PathBuf>,
+}
+
+fn open_input(
+    path: &std::path::Path,
+    gzip: bool,
+    encoding: Option<&'static encoding_rs::Encoding>,
+) -> std::io::Result<Box<dyn std::io::Read>> {
+    let file = std::fs::File::open(path).map_err(|e| {
+        std::io::Error::new(e.kind(), format!("Cannot open {}: {}", path.display(), e))
+    })?;
+    let file: Box<dyn std::io::Read> = if gzip || path.extension().is_some_and(|ext| ext == "gz") {
+        Box::new(flate2::read::GzDecoder::new(file))
+    } else {
+        Box::new(file)
+    };
+    Ok(match encoding {
+        Some(encoding) => Box::new(payment_engine::decoding_reader(file, encoding)),
+        None => file,
+    })
+}
+
+/// 2 for IO errors, 3 for any other processing error
+fn exit_code(e: &(dyn std::error::Error + 'static)) -> i32 {
+    match (
+        e.downcast_ref::<std::io::Error>(),
+        e.downcast_ref::<csv::Error>(),
+        e.downcast_ref::<payment_engine::Error>(),
+    ) {
+        (Some(_), _, _) => 2,
+        (_, Some(e), _) if e.is_io_error() => 2,
+        (_, _, Some(payment_engine::Error::ErrorOutput(_))) => 2,
+        _ => 3,
+    }
 }
 
 fn main() {
+    if let Err(e) = run() {
+        eprintln!("{}", e);
+        std::process::exit(exit_code(e.as_ref()));
+    }
+}
+
+fn run() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
-    let file = std::fs::File::open(args.file_path).expect("Cannot open file for this path");
-    let mut accounts = payment_engine::ClientAccounts::new();
-    let mut operations_register = payment_engine::MoneyOperationsRegister::new();
-    payment_engine::read_transactions_file(
-        file,
-        &mut accounts,
-        &mut operations_register,
-        args.debug,
-    );
-    accounts
-        .print_to(&mut std::io::stdout())
-        .expect("Failed to print the account summary");
+    if !args.quiet {
+        let log_level = if args.debug { "debug" } else { "off" };
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(log_level))
+            .init();
+    }
+    let encoding = args
+        .encoding
+        .as_ref()
+        .map(|label| {
+            encoding_rs::Encoding::for_label(label.as_bytes())
+                .ok_or_else(|| format!("Unknown encoding {}", label))
+        })
+        .transpose()?;
+    let files = args
+        .file_paths
+        .iter()
+        .map(|path| open_input(path, args.gzip, encoding))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    let config = payment_engine::Config {
+        max_disputes: args.max_disputes,
+        max_client_id: args.max_client_id,
+        delimiter: u8::try_from(args.delimiter)
+            .map_err(|_| "The delimiter must be a single byte")?,
+        decimal_separator: args.decimal_separator,
+        strict: args.strict,
+        strict_headers: args.strict_headers,
+        has_headers: !args.no_header,
+        allow_overdraft: args
+            .allow_overdraft
+            .iter()
+            .map(|&id| payment_engine::clients::ClientId(id))
+            .collect(),
+        treat_negative_as_reversal: args.treat_negative_as_reversal,
+        allow_zero_amounts: args.allow_zero_amounts,
+        register_capacity: args.register_capacity,
+        disputable_kinds: args.disputable_kinds.iter().copied().collect(),
+        fee_policy: match (args.withdrawal_fee, args.withdrawal_fee_percent) {
+            (Some(fee), _) => payment_engine::FeePolicy::Flat(fee),
+            (_, Some(rate)) => payment_engine::FeePolicy::Percentage(rate),
+            _ => payment_engine::FeePolicy::NoFee,
+        },
+        fee_collector: payment_engine::clients::ClientId(args.fee_collector),
+        dispute_policy: args.dispute_policy,
+        min_available: args.min_available,
+        max_operations_per_client: args.max_operations_per_client,
+        transaction_id_scope: args.transaction_id_scope,
+        resolve_unlocks: args.resolve_unlocks,
+        locked_allows_deposit: args.locked_allows_deposit,
+        max_rows: args.max_rows,
+        skip_unknown_kinds: args.skip_unknown_kinds,
+        resolve_noop_if_not_disputed: args.resolve_noop_if_not_disputed,
+        reuse_charged_back_ids: args.reuse_charged_back_ids,
+        redispute_allowed: !args.no_redispute,
+        transaction_mode: args.transaction_mode,
+        allowed_kinds: args
+            .allowed_kinds
+            .iter()
+            .filter(|kind| !args.skipped_kinds.contains(kind))
+            .copied()
+            .collect(),
+    };
+    if args.check {
+        // Validated against a shared state, so that files can refer to each other
+        let mut accounts = payment_engine::ClientAccounts::new();
+        let mut operations_register = payment_engine::MoneyOperationsRegister::from_config(&config);
+        let mut rejected = 0;
+        for file in files {
+            rejected += payment_engine::read_transactions_file(
+                file,
+                &mut accounts,
+                &mut operations_register,
+                &config,
+                None,
+                Some(&mut std::io::stdout()),
+                &payment_engine::NoMetrics,
+            )?
+            .rejected();
+        }
+        std::process::exit(if rejected == 0 { 0 } else { 1 });
+    }
+    let (mut accounts, report) = match args.threads {
+        Some(threads) if threads > 1 => payment_engine::process_parallel(files, threads, &config)?,
+        _ if config.transaction_mode == payment_engine::TransactionMode::AllOrNothing => {
+            // Nothing is printed unless every file is accepted
+            let mut accounts = payment_engine::ClientAccounts::new();
+            let mut operations_register =
+                payment_engine::MoneyOperationsRegister::from_config(&config);
+            let mut report = payment_engine::ProcessingReport::default();
+            for (file, path) in files.into_iter().zip(&args.file_paths) {
+                report.merge(
+                    payment_engine::process_all_or_nothing(
+                        file,
+                        &mut accounts,
+                        &mut operations_register,
+                        &config,
+                    )
+                    .map_err(|(line_number, e)| {
+                        format!("{}: line {}: {}", path.display(), line_number, e)
+                    })?,
+                );
+            }
+            (accounts, report)
+        }
+        _ => {
+            let mut accounts = payment_engine::ClientAccounts::new();
+            let mut operations_register =
+                payment_engine::MoneyOperationsRegister::from_config(&config);
+            let mut audit_log = args
+                .audit_log
+                .as_ref()
+                .map(|_| payment_engine::AuditLog::new());
+            let mut report = payment_engine::ProcessingReport::default();
+            for file in files {
+                report.merge(payment_engine::read_transactions_file(
+                    file,
+                    &mut accounts,
+                    &mut operations_register,
+                    &config,
+                    audit_log.as_mut(),
+                    None,
+                    &payment_engine::NoMetrics,
+                )?);
+            }
+            if let (Some(path), Some(audit_log)) = (&args.audit_log, audit_log) {
+                let mut audit_file = std::fs::File::create(path)?;
+                audit_log.write_csv_to(&mut audit_file)?;
+            }
+            (accounts, report)
+        }
+    };
+    log::info!("{}", report);
+    if args.report_anomalies {
+        for (client_id, anomaly) in accounts.anomalies() {
+            eprintln!("Client {} has {}", client_id, anomaly);
+        }
+    }
+    if let Some(path) = &args.diff_against {
+        let previous = payment_engine::ClientAccounts::read_summaries(std::fs::File::open(path)?)?;
+        let mut writer = csv::Writer::from_writer(std::io::stdout());
+        for delta in payment_engine::diff_summaries(&previous, &accounts)? {
+            writer.serialize(delta)?;
+        }
+        writer.flush()?;
+        return Ok(());
+    }
+    accounts.set_rounding_mode(args.rounding);
+    accounts.set_decimals(args.decimals)?;
+    accounts.set_volume_columns(args.volume);
+    if !args.columns.is_empty() {
+        accounts.set_columns(args.columns)?;
+    }
+    match args.format {
+        Format::Csv => accounts.print_to(&mut std::io::stdout())?,
+        Format::Json => accounts.print_json_to(&mut std::io::stdout())?,
+        Format::JsonArray => accounts.print_json_array_to(&mut std::io::stdout())?,
+    }
+    if args.summary {
+        let totals = accounts.platform_totals()?;
+        match args.format {
+            Format::Csv => accounts.print_totals_to(&totals, &mut std::io::stdout())?,
+            Format::Json | Format::JsonArray => {
+                accounts.print_totals_json_to(&totals, &mut std::io::stdout())?
+            }
+        }
+    }
+    Ok(())
 }
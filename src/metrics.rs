@@ -0,0 +1,68 @@
+use crate::TransactionKind;
+
+/// Receives processing statistics, every method does nothing by default.
+pub trait Metrics {
+    fn transaction_processed(&self, _kind: TransactionKind) {}
+    fn transaction_rejected(&self, _category: &'static str) {}
+    fn active_clients(&self, _count: usize) {}
+}
+
+/// Ignores every statistic
+pub struct NoMetrics;
+
+impl Metrics for NoMetrics {}
+
+#[cfg(feature = "prometheus")]
+pub use self::prometheus_metrics::PrometheusMetrics;
+
+#[cfg(feature = "prometheus")]
+mod prometheus_metrics {
+    use {
+        super::Metrics,
+        crate::TransactionKind,
+        prometheus::{IntCounterVec, IntGauge, Opts, Registry},
+    };
+
+    pub struct PrometheusMetrics {
+        transactions: IntCounterVec,
+        errors: IntCounterVec,
+        clients: IntGauge,
+    }
+
+    impl PrometheusMetrics {
+        /// Creates the metrics and registers them in `registry`
+        pub fn new(registry: &Registry) -> prometheus::Result<Self> {
+            let transactions = IntCounterVec::new(
+                Opts::new("transactions_processed_total", "Processed transactions"),
+                &["kind"],
+            )?;
+            let errors = IntCounterVec::new(
+                Opts::new("transactions_rejected_total", "Rejected transactions"),
+                &["category"],
+            )?;
+            let clients = IntGauge::new("active_clients", "Number of client accounts")?;
+            registry.register(Box::new(transactions.clone()))?;
+            registry.register(Box::new(errors.clone()))?;
+            registry.register(Box::new(clients.clone()))?;
+            Ok(Self {
+                transactions,
+                errors,
+                clients,
+            })
+        }
+    }
+
+    impl Metrics for PrometheusMetrics {
+        fn transaction_processed(&self, kind: TransactionKind) {
+            self.transactions
+                .with_label_values(&[&kind.to_string()])
+                .inc();
+        }
+        fn transaction_rejected(&self, category: &'static str) {
+            self.errors.with_label_values(&[category]).inc();
+        }
+        fn active_clients(&self, count: usize) {
+            self.clients.set(count as i64);
+        }
+    }
+}
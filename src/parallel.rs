@@ -0,0 +1,81 @@
+use {
+    crate::{
+        process_line, ClientAccounts, Config, Error, FeePolicy, MoneyOperationsRegister,
+        ProcessingReport, Result, TransactionLine,
+    },
+    std::{io::Read, sync::mpsc, thread},
+};
+
+/// Processes the transactions of the files, one after the other, on `threads` workers,
+/// each one owning the accounts and operations of a shard of clients.
+/// Lines are dispatched by client id, so the transactions of a client are applied in file
+/// order, and the merged accounts print sorted by client id: the output is the same as
+/// the serial one, whatever the number of threads.
+/// The fee collector is credited in every shard and its accounts are summed, so when
+/// fees are charged it can't have transactions of its own: its withdrawals would only
+/// see the fees of its shard. Such an input is refused with `ParallelFeeCollector`.
+/// Transaction ids are expected to be unique across clients: a deposit or withdrawal
+/// reusing the id of another client's transaction is only rejected within its shard.
+/// Rejected lines are logged.
+pub fn process_parallel<R: Read>(
+    files: impl IntoIterator<Item = R>,
+    threads: usize,
+    config: &Config,
+) -> Result<(ClientAccounts, ProcessingReport)> {
+    let threads = threads.max(1);
+    let charges_fees = config.fee_policy != FeePolicy::NoFee;
+    let (senders, workers): (Vec<_>, Vec<_>) = (0..threads)
+        .map(|_| {
+            let (sender, receiver) = mpsc::channel::<(usize, TransactionLine)>();
+            let config = config.clone();
+            let worker = thread::spawn(move || {
+                let mut accounts = ClientAccounts::new();
+                let mut operations_register = MoneyOperationsRegister::from_config(&config);
+                let mut report = ProcessingReport::default();
+                for (line_number, line) in receiver {
+                    let result =
+                        process_line(line, &mut accounts, &mut operations_register, &config, None);
+                    report.record(&result);
+                    if let Err(e) = &result {
+                        crate::log_error(line_number, e);
+                    }
+                }
+                (accounts, report)
+            });
+            (sender, worker)
+        })
+        .unzip();
+
+    let mut report = ProcessingReport::default();
+    let mut fee_collector_found = false;
+    for (line_number, line) in files
+        .into_iter()
+        .flat_map(|file| crate::numbered_lines(file, config))
+    {
+        match line {
+            Ok(line) if charges_fees && line.client_id == config.fee_collector => {
+                fee_collector_found = true;
+                break;
+            }
+            Ok(line) => senders[line.client_id.0 as usize % threads]
+                .send((line_number, line))
+                .expect("Worker thread stopped unexpectedly"),
+            Err(e) => {
+                crate::log_error(line_number, &e);
+                report.record(&Err(e));
+            }
+        }
+    }
+    drop(senders);
+
+    let mut accounts = ClientAccounts::new();
+    for worker in workers {
+        let (shard_accounts, shard_report) = worker.join().expect("Worker thread panicked");
+        accounts.merge(shard_accounts)?;
+        report.merge(shard_report);
+    }
+    if fee_collector_found {
+        return Err(Error::ParallelFeeCollector(config.fee_collector));
+    }
+    Ok((accounts, report))
+}
@@ -0,0 +1,76 @@
+use {
+    crate::{ClientId, Error, TransactionError},
+    serde::Serialize,
+    std::collections::BTreeMap,
+};
+
+#[derive(Debug, Default, Serialize)]
+pub struct ProcessingReport {
+    pub lines: usize,
+    pub processed: usize,
+    /// Lines of a kind that isn't allowed by the config
+    pub skipped: usize,
+    /// Lines with only whitespace
+    pub blank: usize,
+    /// Rejected lines, grouped by error category
+    pub errors: BTreeMap<&'static str, usize>,
+    /// Transactions rejected because the account was already locked, per client
+    pub dropped_post_lock: BTreeMap<ClientId, usize>,
+}
+
+impl ProcessingReport {
+    pub fn record(&mut self, result: &crate::Result<()>) {
+        self.lines += 1;
+        match result {
+            Ok(()) => self.processed += 1,
+            Err(Error::BlankLine) => self.blank += 1,
+            Err(e) if e.is_skip() => self.skipped += 1,
+            Err(e) => {
+                if let Error::TransactionError(TransactionError::LockedAccount(client_id)) = e {
+                    *self.dropped_post_lock.entry(*client_id).or_insert(0) += 1;
+                }
+                *self.errors.entry(e.category()).or_insert(0) += 1
+            }
+        }
+    }
+    pub fn merge(&mut self, other: ProcessingReport) {
+        self.lines += other.lines;
+        self.processed += other.processed;
+        self.skipped += other.skipped;
+        self.blank += other.blank;
+        for (category, count) in other.errors {
+            *self.errors.entry(category).or_insert(0) += count;
+        }
+        for (client_id, count) in other.dropped_post_lock {
+            *self.dropped_post_lock.entry(client_id).or_insert(0) += count;
+        }
+    }
+    pub fn rejected(&self) -> usize {
+        self.lines - self.processed - self.skipped - self.blank
+    }
+}
+
+impl std::fmt::Display for ProcessingReport {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            fmt,
+            "{} lines read, {} processed, {} skipped, {} blank, {} rejected",
+            self.lines,
+            self.processed,
+            self.skipped,
+            self.blank,
+            self.rejected()
+        )?;
+        for (category, count) in &self.errors {
+            write!(fmt, "\n  {}: {}", category, count)?;
+        }
+        for (client_id, count) in &self.dropped_post_lock {
+            write!(
+                fmt,
+                "\n  dropped after client {} got locked: {}",
+                client_id, count
+            )?;
+        }
+        Ok(())
+    }
+}
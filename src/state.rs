@@ -0,0 +1,91 @@
+use {
+    crate::{
+        transactions::{ClientClaimKind, MoneyOperation, OperationKey},
+        Amount, Client, ClientAccounts, ClientId, Currency, MoneyOperationsRegister,
+        TransactionIdScope,
+    },
+    chrono::{DateTime, Utc},
+    serde::{Deserialize, Serialize},
+    std::io::{Read, Write},
+};
+
+/// Everything needed to resume processing where it stopped.
+#[derive(Deserialize)]
+struct EngineState {
+    clients: Vec<Client>,
+    operations: Vec<MoneyOperation>,
+    applied_claims: Vec<(OperationKey, ClientClaimKind)>,
+    #[serde(default)]
+    scope: TransactionIdScope,
+    #[serde(default)]
+    capacity: Option<usize>,
+    #[serde(default)]
+    operation_counts: Vec<(ClientId, u32)>,
+    /// Dated funds movements, for the summaries over a time window
+    #[serde(default)]
+    history: Vec<(ClientId, Currency, DateTime<Utc>, Amount)>,
+    #[serde(default)]
+    evicted: Vec<OperationKey>,
+}
+
+/// Writes the balances and the operations register as JSON.
+pub fn save_state<W: Write>(
+    w: W,
+    accounts: &ClientAccounts,
+    operations_register: &MoneyOperationsRegister,
+) -> Result<(), serde_json::Error> {
+    #[derive(Serialize)]
+    struct EngineStateRef<'a> {
+        clients: Vec<&'a Client>,
+        operations: Vec<&'a MoneyOperation>,
+        applied_claims: Vec<(&'a OperationKey, &'a ClientClaimKind)>,
+        scope: TransactionIdScope,
+        capacity: Option<usize>,
+        operation_counts: Vec<(&'a ClientId, &'a u32)>,
+        history: &'a [(ClientId, Currency, DateTime<Utc>, Amount)],
+        evicted: Vec<&'a OperationKey>,
+    }
+
+    serde_json::to_writer(
+        w,
+        &EngineStateRef {
+            clients: accounts.inner.values().collect(),
+            operations: operations_register
+                .order
+                .iter()
+                .filter_map(|id| operations_register.inner.get(id))
+                .collect(),
+            applied_claims: operations_register.applied_claims.iter().collect(),
+            scope: operations_register.scope,
+            capacity: operations_register.capacity,
+            operation_counts: operations_register.operation_counts.iter().collect(),
+            history: &accounts.history,
+            evicted: operations_register.evicted.iter().collect(),
+        },
+    )
+}
+
+/// Reads a state written by `save_state`.
+pub fn load_state<R: Read>(
+    r: R,
+) -> Result<(ClientAccounts, MoneyOperationsRegister), serde_json::Error> {
+    let state: EngineState = serde_json::from_reader(r)?;
+    let mut accounts = ClientAccounts::new();
+    accounts.inner = state
+        .clients
+        .into_iter()
+        .map(|client| ((client.id, client.currency.clone()), client))
+        .collect();
+    accounts.history = state.history;
+    let mut operations_register = MoneyOperationsRegister::new();
+    operations_register.scope = state.scope;
+    for operation in state.operations {
+        operations_register.insert(operation);
+    }
+    operations_register.applied_claims = state.applied_claims.into_iter().collect();
+    // Restored after the operations, which were within the capacity when saved
+    operations_register.capacity = state.capacity;
+    operations_register.operation_counts = state.operation_counts.into_iter().collect();
+    operations_register.evicted = state.evicted.into_iter().collect();
+    Ok((accounts, operations_register))
+}
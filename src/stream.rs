@@ -0,0 +1,31 @@
+use {
+    crate::{
+        log_error, process_line, ClientAccounts, Config, MoneyOperationsRegister, ProcessingReport,
+        TransactionLine,
+    },
+    futures::{Stream, StreamExt},
+};
+
+/// Processes the lines as they arrive. The next line is only pulled once the previous
+/// one is processed, and the task yields between lines so that other tasks can run.
+pub async fn process_stream<S>(
+    mut stream: S,
+    accounts: &mut ClientAccounts,
+    operations_register: &mut MoneyOperationsRegister,
+    config: &Config,
+) -> ProcessingReport
+where
+    S: Stream<Item = TransactionLine> + Unpin,
+{
+    let mut report = ProcessingReport::default();
+    while let Some(line) = stream.next().await {
+        let result = process_line(line, accounts, operations_register, config, None);
+        report.record(&result);
+        if let Err(e) = &result {
+            // Items are numbered from 1, as lines of a file without header
+            log_error(report.lines, e);
+        }
+        tokio::task::yield_now().await;
+    }
+    report
+}
@@ -1,20 +1,60 @@
-use crate::{ClientAccounts, ClientId};
+use crate::{
+    clients::{Currency, IdVisitor, LockReason},
+    Amount, AuditEntry, AuditLog, ClientAccounts, ClientId, Config, DisputePolicy,
+    TransactionIdScope, TransactionKind,
+};
 use {
-    std::{collections::HashMap, fmt},
-    strum_macros::Display,
+    chrono::{DateTime, Utc},
+    serde::{Deserialize, Deserializer, Serialize},
+    std::{
+        collections::{HashMap, HashSet, VecDeque},
+        fmt,
+    },
+    strum_macros::IntoStaticStr,
 };
 
-#[derive(Copy, Clone, Debug, Default, Hash, Eq, PartialEq, serde::Deserialize)]
+#[derive(Copy, Clone, Debug, Default, Hash, Eq, PartialEq, Serialize)]
 pub struct TransactionId(pub u32);
 
-#[derive(Debug)]
+impl<'de> Deserialize<'de> for TransactionId {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer
+            .deserialize_any(IdVisitor("transaction"))
+            .map(TransactionId)
+    }
+}
+
+impl fmt::Display for TransactionId {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{}", self.0)
+    }
+}
+
+#[derive(Debug, IntoStaticStr)]
 pub enum TransactionError {
     AlreadyExists(TransactionId),
+    AlreadyResolved(TransactionId),
+    ClientMismatch {
+        claim: ClientId,
+        operation: ClientId,
+    },
+    DisputeExceedsAmount(TransactionId),
+    DisputeLimitExceeded(TransactionId),
+    DuplicateClaim(TransactionId),
     LockedAccount(ClientId),
     MissingClient(ClientId),
     MissingOperation(TransactionId),
+    NotDisputable(TransactionId),
     NotEnoughFunds,
+    Overflow(ClientId),
+    RateLimited(ClientId),
+    WouldExceedExposure(ClientId),
+    WouldGoNegative(ClientId),
     WrongTransactionState,
+    ZeroAmount(TransactionId),
 }
 
 impl fmt::Display for TransactionError {
@@ -25,53 +65,224 @@ impl fmt::Display for TransactionError {
             match *self {
                 Self::AlreadyExists(TransactionId(id)) =>
                     format!("Transaction {} already exists", id),
+                Self::AlreadyResolved(TransactionId(transaction_id)) => format!(
+                    "Transaction {} was already disputed and resolved",
+                    transaction_id
+                ),
+                Self::ClientMismatch {
+                    claim: ClientId(claim),
+                    operation: ClientId(operation),
+                } => format!(
+                    "Client {} can't claim a transaction of client {}",
+                    claim, operation
+                ),
+                Self::DisputeExceedsAmount(TransactionId(transaction_id)) => format!(
+                    "Disputed amount exceeds the amount of transaction {}",
+                    transaction_id
+                ),
+                Self::DisputeLimitExceeded(TransactionId(transaction_id)) =>
+                    format!("Transaction {} can't be disputed again", transaction_id),
+                Self::DuplicateClaim(TransactionId(transaction_id)) => format!(
+                    "Same claim already applied to transaction {}",
+                    transaction_id
+                ),
                 Self::LockedAccount(ClientId(client_id)) =>
                     format!("Client account {} is locked", client_id),
                 Self::MissingClient(ClientId(client_id)) =>
                     format!("Can't find client {}", client_id),
                 Self::MissingOperation(TransactionId(transaction_id)) =>
                     format!("Can't find transaction {}", transaction_id),
+                Self::NotDisputable(TransactionId(transaction_id)) =>
+                    format!("Transaction {} can't be disputed", transaction_id),
                 Self::NotEnoughFunds => String::from("Not enough funds"),
+                Self::Overflow(ClientId(client_id)) =>
+                    format!("Balance of client {} would overflow", client_id),
+                Self::RateLimited(ClientId(client_id)) =>
+                    format!("Client {} made too many operations", client_id),
+                Self::WouldExceedExposure(ClientId(client_id)) => format!(
+                    "Available funds of client {} would go below the floor",
+                    client_id
+                ),
+                Self::WouldGoNegative(ClientId(client_id)) =>
+                    format!("Available funds of client {} would go negative", client_id),
                 Self::WrongTransactionState => String::from("Wrong transaction state"),
+                Self::ZeroAmount(TransactionId(transaction_id)) =>
+                    format!("Transaction {} has a zero amount", transaction_id),
             }
         )
     }
 }
-#[derive(Debug, Display)]
+
+impl std::error::Error for TransactionError {}
+
+#[derive(Debug)]
 pub enum TransactionOrder {
     MoneyOperation(MoneyOperation),
     ClientClaim(ClientClaim),
 }
 
+impl fmt::Display for TransactionOrder {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            fmt,
+            "{} client={} tx={}",
+            self.kind(),
+            self.client_id(),
+            self.transaction_id()
+        )?;
+        match self {
+            Self::MoneyOperation(MoneyOperation {
+                operation_kind: OperationKind::Deposit(amount),
+                ..
+            })
+            | Self::MoneyOperation(MoneyOperation {
+                operation_kind: OperationKind::Withdrawal(amount),
+                ..
+            })
+            | Self::MoneyOperation(MoneyOperation {
+                operation_kind: OperationKind::Authorization(amount),
+                ..
+            })
+            | Self::ClientClaim(ClientClaim {
+                amount: Some(amount),
+                ..
+            }) => write!(fmt, " amount={}", amount),
+            Self::ClientClaim(_) => Ok(()),
+        }
+    }
+}
+
 impl TransactionOrder {
     pub fn process(
         self,
         clients_map: &mut ClientAccounts,
         operations_register: &mut MoneyOperationsRegister,
+        config: &Config,
+        audit_log: Option<&mut AuditLog>,
     ) -> Result<(), TransactionError> {
-        match self {
+        let (tx, client, kind) = (self.transaction_id(), self.client_id(), self.kind());
+        let currency = self.currency(operations_register);
+        let (available_before, held_before) = clients_map.balances(client, &currency);
+        let result = match self {
             Self::MoneyOperation(money_operation) => {
-                money_operation.process(clients_map, operations_register)
+                money_operation.process(clients_map, operations_register, config)
             }
             Self::ClientClaim(client_claim) => {
-                client_claim.process(clients_map, operations_register)
+                client_claim.process(clients_map, operations_register, config)
             }
+        };
+        if let Some(audit_log) = audit_log {
+            let (available_after, held_after) = clients_map.balances(client, &currency);
+            audit_log.push(AuditEntry {
+                tx,
+                client,
+                kind,
+                available_before,
+                held_before,
+                available_after,
+                held_after,
+                result: match &result {
+                    Ok(()) => String::from("ok"),
+                    Err(e) => e.to_string(),
+                },
+            });
+        }
+        result
+    }
+    /// Currency of the account the order applies to, claims apply to the account of
+    /// the claimed transaction
+    pub fn currency(&self, operations_register: &MoneyOperationsRegister) -> Currency {
+        match self {
+            Self::MoneyOperation(money_operation) => money_operation.currency.clone(),
+            Self::ClientClaim(client_claim) => operations_register
+                .operation(client_claim.client_id, client_claim.transaction_id)
+                .map(|operation| operation.currency.clone())
+                .unwrap_or_default(),
+        }
+    }
+    pub fn client_id(&self) -> ClientId {
+        match self {
+            Self::MoneyOperation(money_operation) => money_operation.client_id,
+            Self::ClientClaim(client_claim) => client_claim.client_id,
+        }
+    }
+    pub fn transaction_id(&self) -> TransactionId {
+        match self {
+            Self::MoneyOperation(money_operation) => money_operation.transaction_id,
+            Self::ClientClaim(client_claim) => client_claim.transaction_id,
+        }
+    }
+    pub fn kind(&self) -> TransactionKind {
+        match self {
+            Self::MoneyOperation(MoneyOperation {
+                operation_kind: OperationKind::Deposit(_),
+                ..
+            }) => TransactionKind::Deposit,
+            Self::MoneyOperation(MoneyOperation {
+                operation_kind: OperationKind::Withdrawal(_),
+                ..
+            }) => TransactionKind::Withdrawal,
+            Self::MoneyOperation(MoneyOperation {
+                operation_kind: OperationKind::Authorization(_),
+                ..
+            }) => TransactionKind::Authorize,
+            Self::ClientClaim(client_claim) => match client_claim.claim_kind {
+                ClientClaimKind::Resolve => TransactionKind::Resolve,
+                ClientClaimKind::Dispute => TransactionKind::Dispute,
+                ClientClaimKind::Chargeback => TransactionKind::Chargeback,
+                ClientClaimKind::Unlock => TransactionKind::Unlock,
+                ClientClaimKind::Close => TransactionKind::Close,
+                ClientClaimKind::Accrue => TransactionKind::Accrue,
+                ClientClaimKind::Capture => TransactionKind::Capture,
+                ClientClaimKind::Void => TransactionKind::Void,
+            },
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct MoneyOperation {
     pub client_id: ClientId,
     pub transaction_id: TransactionId,
     pub disputed: bool,
+    pub dispute_count: u32,
+    /// Amount held by the ongoing disputes, released by their resolve or chargeback.
+    /// It is tracked per transaction, so claims on several disputed transactions of
+    /// a client release exactly their own amount, in whatever order they come.
+    pub held_for_dispute: Amount,
+    /// A dispute of the transaction was resolved
+    #[serde(default)]
+    pub resolved_once: bool,
     pub operation_kind: OperationKind,
+    #[serde(default)]
+    pub currency: Currency,
+    #[serde(default)]
+    pub timestamp: Option<DateTime<Utc>>,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub enum OperationKind {
-    Deposit(f64),
-    Withdrawal(f64),
+    Deposit(Amount),
+    Withdrawal(Amount),
+    /// Funds held until the authorization is captured or voided
+    Authorization(Amount),
+}
+
+impl OperationKind {
+    pub fn transaction_kind(&self) -> TransactionKind {
+        match self {
+            Self::Deposit(_) => TransactionKind::Deposit,
+            Self::Withdrawal(_) => TransactionKind::Withdrawal,
+            Self::Authorization(_) => TransactionKind::Authorize,
+        }
+    }
+    pub fn amount(&self) -> Amount {
+        match *self {
+            Self::Deposit(amount) | Self::Withdrawal(amount) | Self::Authorization(amount) => {
+                amount
+            }
+        }
+    }
 }
 
 impl MoneyOperation {
@@ -79,54 +290,241 @@ impl MoneyOperation {
         self,
         clients_map: &mut ClientAccounts,
         operations_register: &mut MoneyOperationsRegister,
+        config: &Config,
     ) -> Result<(), TransactionError> {
-        if operations_register.contains(&self.transaction_id) {
+        if operations_register.is_used(self.client_id, self.transaction_id) {
             return Err(TransactionError::AlreadyExists(self.transaction_id));
         }
+        let count = operations_register.operation_count(self.client_id);
+        if matches!(config.max_operations_per_client, Some(max) if count >= max) {
+            return Err(TransactionError::RateLimited(self.client_id));
+        }
+        // A zero amount is most likely an upstream bug
+        if self.operation_kind.amount() == Amount::ZERO && !config.allow_zero_amounts {
+            return Err(TransactionError::ZeroAmount(self.transaction_id));
+        }
+        // Funds moved, negative for a withdrawal
+        let moved = match self.operation_kind {
+            OperationKind::Deposit(amount) => amount,
+            OperationKind::Withdrawal(amount) => amount
+                .checked_neg()
+                .ok_or(TransactionError::Overflow(self.client_id))?,
+            OperationKind::Authorization(_) => Amount::ZERO,
+        };
         match (
             &self.operation_kind,
-            clients_map.get_account(self.client_id),
+            clients_map.get_currency_account(self.client_id, &self.currency),
         ) {
-            (_, Some(client)) if client.locked => {
+            (OperationKind::Deposit(_), Some(client))
+                if client.locked && !config.locked_allows_deposit =>
+            {
                 return Err(TransactionError::LockedAccount(self.client_id))
             }
-            (OperationKind::Withdrawal(_), None) => {
+            (OperationKind::Withdrawal(_), Some(client))
+            | (OperationKind::Authorization(_), Some(client))
+                if client.locked =>
+            {
+                return Err(TransactionError::LockedAccount(self.client_id))
+            }
+            (OperationKind::Withdrawal(_), None) | (OperationKind::Authorization(_), None) => {
                 return Err(TransactionError::MissingClient(self.client_id))
             }
-            (OperationKind::Withdrawal(amount), Some(client)) => {
+            (OperationKind::Authorization(amount), Some(client)) => {
                 if !client.has_enough_funds(*amount) {
                     return Err(TransactionError::NotEnoughFunds);
                 }
-                client.decrease_funds(*amount)
+                client.hold_funds(*amount)?
+            }
+            (OperationKind::Withdrawal(amount), Some(client)) => {
+                // The fee is taken on top of the amount and goes to the fee collector,
+                // it is not given back by a dispute
+                let fee = config
+                    .fee_policy
+                    .fee(*amount)
+                    .ok_or(TransactionError::Overflow(self.client_id))?;
+                let debited = amount
+                    .checked_add(fee)
+                    .ok_or(TransactionError::Overflow(self.client_id))?;
+                if !client.has_enough_funds(debited)
+                    && !config.allow_overdraft.contains(&self.client_id)
+                {
+                    return Err(TransactionError::NotEnoughFunds);
+                }
+                // Checked before the fee is credited so that nothing is applied on failure
+                if client.available().checked_sub(debited).is_none() {
+                    return Err(TransactionError::Overflow(self.client_id));
+                }
+                if fee > Amount::ZERO {
+                    clients_map.credit(config.fee_collector, &self.currency, fee)?;
+                }
+                if let Some(client) =
+                    clients_map.get_currency_account(self.client_id, &self.currency)
+                {
+                    client.decrease_funds(debited)?
+                }
             }
-            (OperationKind::Deposit(amount), Some(client)) => client.increase_funds(*amount),
+            (OperationKind::Deposit(amount), Some(client)) => client.increase_funds(*amount)?,
             (OperationKind::Deposit(amount), None) => {
-                clients_map.create_client(self.client_id, *amount)
+                clients_map.create_account(self.client_id, self.currency.clone(), *amount)
             }
         }
-        operations_register.insert(self.transaction_id, self);
+        if let Some(client) = clients_map.get_currency_account(self.client_id, &self.currency) {
+            match self.operation_kind {
+                OperationKind::Deposit(amount) => client.record_deposit(amount),
+                OperationKind::Withdrawal(amount) => client.record_withdrawal(amount),
+                // Recorded as withdrawn when captured
+                OperationKind::Authorization(_) => (),
+            }
+        }
+        if let Some(timestamp) = self.timestamp {
+            clients_map.record_dated(self.client_id, self.currency.clone(), timestamp, moved);
+        }
+        operations_register.count_operation(self.client_id);
+        operations_register.insert(self);
         Ok(())
     }
 }
 
+/// Key of an operation in the register, the client is only part of it when
+/// transaction ids are scoped per client
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq, Deserialize, Serialize)]
+pub(crate) struct OperationKey(Option<ClientId>, TransactionId);
+
+#[derive(Clone)]
 pub struct MoneyOperationsRegister {
-    inner: HashMap<TransactionId, MoneyOperation>,
+    pub(crate) inner: HashMap<OperationKey, MoneyOperation>,
+    /// Last claim applied to each transaction
+    pub(crate) applied_claims: HashMap<OperationKey, ClientClaimKind>,
+    /// Operations from the oldest to the newest
+    pub(crate) order: VecDeque<OperationKey>,
+    pub(crate) capacity: Option<usize>,
+    pub(crate) scope: TransactionIdScope,
+    /// Accepted operations of each client, evicted ones included
+    pub(crate) operation_counts: HashMap<ClientId, u32>,
+    /// Keys of the evicted operations, whose ids can't be reused
+    pub(crate) evicted: HashSet<OperationKey>,
+}
+
+impl Default for MoneyOperationsRegister {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl MoneyOperationsRegister {
     pub fn new() -> Self {
         Self {
             inner: HashMap::new(),
+            applied_claims: HashMap::new(),
+            order: VecDeque::new(),
+            capacity: None,
+            scope: TransactionIdScope::default(),
+            operation_counts: HashMap::new(),
+            evicted: HashSet::new(),
+        }
+    }
+    /// Register with the capacity and the transaction id scope of the config
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            capacity: config.register_capacity,
+            scope: config.transaction_id_scope,
+            ..Self::new()
+        }
+    }
+    /// Keeps at most `capacity` operations, evicting the oldest undisputed ones.
+    /// Evicted transactions can't be claimed anymore, but their ids are remembered so
+    /// that a new operation can't reuse them.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            capacity: Some(capacity),
+            ..Self::new()
+        }
+    }
+    fn key(&self, client_id: ClientId, id: TransactionId) -> OperationKey {
+        match self.scope {
+            TransactionIdScope::Global => OperationKey(None, id),
+            TransactionIdScope::PerClient => OperationKey(Some(client_id), id),
         }
     }
-    pub fn contains(&self, id: &TransactionId) -> bool {
-        self.inner.get(&id).is_some()
+    pub fn last_claim(&self, client_id: ClientId, id: TransactionId) -> Option<ClientClaimKind> {
+        self.applied_claims.get(&self.key(client_id, id)).copied()
+    }
+    pub fn record_claim(
+        &mut self,
+        client_id: ClientId,
+        id: TransactionId,
+        claim_kind: ClientClaimKind,
+    ) {
+        self.applied_claims
+            .insert(self.key(client_id, id), claim_kind);
+    }
+    pub fn operation_count(&self, client_id: ClientId) -> u32 {
+        self.operation_counts.get(&client_id).copied().unwrap_or(0)
+    }
+    pub fn count_operation(&mut self, client_id: ClientId) {
+        *self.operation_counts.entry(client_id).or_insert(0) += 1;
+    }
+    /// With a global scope, the operation may belong to another client
+    pub fn contains(&self, client_id: ClientId, id: TransactionId) -> bool {
+        self.inner.contains_key(&self.key(client_id, id))
+    }
+    /// The id is taken by an operation, kept or evicted
+    pub fn is_used(&self, client_id: ClientId, id: TransactionId) -> bool {
+        let key = self.key(client_id, id);
+        self.inner.contains_key(&key) || self.evicted.contains(&key)
+    }
+    pub fn operation(&self, client_id: ClientId, id: TransactionId) -> Option<&MoneyOperation> {
+        self.inner.get(&self.key(client_id, id))
     }
-    pub fn get_operation(&mut self, id: TransactionId) -> Option<&mut MoneyOperation> {
-        self.inner.get_mut(&id)
+    pub fn get_operation(
+        &mut self,
+        client_id: ClientId,
+        id: TransactionId,
+    ) -> Option<&mut MoneyOperation> {
+        let key = self.key(client_id, id);
+        self.inner.get_mut(&key)
     }
-    pub fn insert(&mut self, id: TransactionId, operation: MoneyOperation) {
-        self.inner.insert(id, operation);
+    /// The operation won't be claimed anymore: it is forgotten, with its claims,
+    /// so that a new operation can reuse its id
+    pub fn mark_terminal(&mut self, client_id: ClientId, id: TransactionId) {
+        let key = self.key(client_id, id);
+        self.inner.remove(&key);
+        self.applied_claims.remove(&key);
+        self.order.retain(|&other| other != key);
+    }
+    pub fn insert(&mut self, operation: MoneyOperation) {
+        let key = self.key(operation.client_id, operation.transaction_id);
+        if self.inner.insert(key, operation).is_none() {
+            self.order.push_back(key);
+        }
+        self.evict();
+    }
+    fn evict(&mut self) {
+        let capacity = match self.capacity {
+            Some(capacity) => capacity,
+            None => return,
+        };
+        // Disputed operations are kept until their dispute is settled, and
+        // authorizations until they are captured or voided
+        for _ in 0..self.order.len() {
+            if self.inner.len() <= capacity {
+                break;
+            }
+            if let Some(id) = self.order.pop_front() {
+                let open = !self.applied_claims.contains_key(&id);
+                if self.inner.get(&id).is_some_and(|operation| {
+                    operation.disputed
+                        || (open
+                            && matches!(operation.operation_kind, OperationKind::Authorization(_)))
+                }) {
+                    self.order.push_back(id);
+                } else {
+                    self.inner.remove(&id);
+                    self.applied_claims.remove(&id);
+                    self.evicted.insert(id);
+                }
+            }
+        }
     }
 }
 
@@ -135,13 +533,26 @@ pub struct ClientClaim {
     pub client_id: ClientId,
     pub transaction_id: TransactionId,
     pub claim_kind: ClientClaimKind,
+    /// Partial amount of a dispute, the whole transaction if `None`
+    pub amount: Option<Amount>,
 }
 
-#[derive(Debug)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
 pub enum ClientClaimKind {
     Resolve,
     Dispute,
     Chargeback,
+    /// Manual unlock of an account locked by a chargeback, the transaction id is
+    /// only a reference
+    Unlock,
+    /// Removal of the accounts of a client, which must be empty
+    Close,
+    /// Interest on the funds held by the disputes of a client, the amount is the rate
+    Accrue,
+    /// Debit of the funds held by an authorization
+    Capture,
+    /// Release of the funds held by an authorization
+    Void,
 }
 
 impl ClientClaim {
@@ -149,55 +560,234 @@ impl ClientClaim {
         self,
         clients_map: &mut ClientAccounts,
         operations_register: &mut MoneyOperationsRegister,
+        config: &Config,
     ) -> Result<(), TransactionError> {
+        if self.claim_kind == ClientClaimKind::Unlock {
+            // Unlocks the accounts of the client in every currency
+            let mut found = false;
+            let mut unlocked = false;
+            for client in clients_map.accounts_of(self.client_id) {
+                found = true;
+                if client.locked {
+                    client.unlock();
+                    unlocked = true;
+                }
+            }
+            return match (found, unlocked) {
+                (_, true) => Ok(()),
+                (true, false) => Err(TransactionError::WrongTransactionState),
+                (false, _) => Err(TransactionError::MissingClient(self.client_id)),
+            };
+        }
+        if self.claim_kind == ClientClaimKind::Accrue {
+            let rate = self.amount.unwrap_or(Amount::ZERO);
+            if clients_map
+                .accounts_of(self.client_id)
+                .any(|client| client.locked)
+            {
+                return Err(TransactionError::LockedAccount(self.client_id));
+            }
+            let mut found = false;
+            for client in clients_map.accounts_of(self.client_id) {
+                found = true;
+                for (transaction_id, held) in client.accrue_held(rate)? {
+                    if let Some(operation) =
+                        operations_register.get_operation(self.client_id, transaction_id)
+                    {
+                        operation.held_for_dispute = held;
+                    }
+                }
+            }
+            return if found {
+                Ok(())
+            } else {
+                Err(TransactionError::MissingClient(self.client_id))
+            };
+        }
+        if self.claim_kind == ClientClaimKind::Close {
+            let funded = {
+                let mut accounts = clients_map.accounts_of(self.client_id).peekable();
+                if accounts.peek().is_none() {
+                    return Err(TransactionError::MissingClient(self.client_id));
+                }
+                accounts.any(|client| {
+                    client.available() != Amount::ZERO || client.held() != Amount::ZERO
+                })
+            };
+            if funded {
+                return Err(TransactionError::WrongTransactionState);
+            }
+            clients_map.remove_client(self.client_id);
+            return Ok(());
+        }
+        let last_claim = operations_register.last_claim(self.client_id, self.transaction_id);
+        let currency = operations_register
+            .operation(self.client_id, self.transaction_id)
+            .map(|operation| operation.currency.clone())
+            .unwrap_or_default();
+        // A void only gives held funds back, so it is allowed on a locked account
+        let voids_authorization = self.claim_kind == ClientClaimKind::Void
+            && operations_register
+                .operation(self.client_id, self.transaction_id)
+                .is_some_and(|operation| {
+                    matches!(operation.operation_kind, OperationKind::Authorization(_))
+                });
         let (operation, client) = match (
-            operations_register.get_operation(self.transaction_id),
-            clients_map.get_account(self.client_id),
+            operations_register.get_operation(self.client_id, self.transaction_id),
+            clients_map.get_currency_account(self.client_id, &currency),
         ) {
-            (_, Some(client)) if client.locked => {
+            (Some(operation), _) if operation.client_id != self.client_id => {
+                return Err(TransactionError::ClientMismatch {
+                    claim: self.client_id,
+                    operation: operation.client_id,
+                })
+            }
+            (_, Some(client))
+                if client.locked
+                    && !voids_authorization
+                    && !(config.resolve_unlocks && self.claim_kind == ClientClaimKind::Resolve) =>
+            {
                 return Err(TransactionError::LockedAccount(self.client_id))
             }
             (Some(operation), Some(client)) => (operation, client),
             (None, _) => return Err(TransactionError::MissingOperation(self.transaction_id)),
             (_, None) => return Err(TransactionError::MissingClient(self.client_id)),
         };
+        // Applying the same claim twice in a row is a replay, not a state error.
+        // Partial disputes can follow each other though, and replayed resolves
+        // may be no-ops. The owner and the lock are checked first, so that a replay
+        // by another client or on a locked account is reported as such.
+        let noop_resolve =
+            self.claim_kind == ClientClaimKind::Resolve && config.resolve_noop_if_not_disputed;
+        if last_claim == Some(self.claim_kind) && self.amount.is_none() && !noop_resolve {
+            return Err(TransactionError::DuplicateClaim(self.transaction_id));
+        }
 
-        match self.claim_kind {
-            ClientClaimKind::Dispute if !operation.disputed => {
-                // Negative funds are accepted when it's due to disputes
+        // Balance changes for a disputed amount `x`:
+        //
+        // | claim      | deposit              | withdrawal           |
+        // |------------|----------------------|----------------------|
+        // | dispute    | available -x, held +x| held +x              |
+        // | resolve    | available +x, held -x| held -x              |
+        // | chargeback | held -x              | available +x, held -x|
+        //
+        // A charged back withdrawal gives the money back to the client, a resolved
+        // one stands and the total is back to what it was before the dispute.
+        match (self.claim_kind, &operation.operation_kind) {
+            // An authorization is settled once, by a capture or a void
+            (ClientClaimKind::Capture, &OperationKind::Authorization(amount))
+            | (ClientClaimKind::Void, &OperationKind::Authorization(amount)) => {
+                if last_claim.is_some() {
+                    return Err(TransactionError::WrongTransactionState);
+                }
+                if self.claim_kind == ClientClaimKind::Capture {
+                    client.clear_held_funds(amount)?;
+                    client.record_withdrawal(amount);
+                } else {
+                    client.release_funds(amount)?;
+                }
+            }
+            (ClientClaimKind::Capture, _) | (ClientClaimKind::Void, _) => {
+                return Err(TransactionError::WrongTransactionState)
+            }
+            (_, OperationKind::Authorization(_)) => {
+                return Err(TransactionError::NotDisputable(self.transaction_id))
+            }
+            (ClientClaimKind::Dispute, _) if !operation.disputed || self.amount.is_some() => {
+                if !config
+                    .disputable_kinds
+                    .contains(&operation.operation_kind.transaction_kind())
+                {
+                    return Err(TransactionError::NotDisputable(self.transaction_id));
+                }
+                if !operation.disputed && operation.resolved_once && !config.redispute_allowed {
+                    return Err(TransactionError::AlreadyResolved(self.transaction_id));
+                }
+                if !operation.disputed
+                    && matches!(config.max_disputes, Some(max) if operation.dispute_count >= max)
+                {
+                    return Err(TransactionError::DisputeLimitExceeded(self.transaction_id));
+                }
+                // Partial disputes add up, up to the amount of the transaction
+                let amount = self
+                    .amount
+                    .unwrap_or_else(|| operation.operation_kind.amount());
+                let held_for_dispute = operation
+                    .held_for_dispute
+                    .checked_add(amount)
+                    .filter(|&held| held <= operation.operation_kind.amount())
+                    .ok_or(TransactionError::DisputeExceedsAmount(self.transaction_id))?;
+                // A dispute never decreases the total: a disputed deposit moves
+                // its amount from available to held, a disputed withdrawal
+                // brings its amount back as held funds.
+                // Negative available funds are accepted when it's due to disputes,
+                // unless the policy says otherwise
                 match operation.operation_kind {
-                    OperationKind::Deposit(amount) => {
-                        client.hold_funds(amount);
-                        client.decrease_funds(amount)
+                    OperationKind::Deposit(_) => {
+                        if config.dispute_policy == DisputePolicy::RejectIfNegative
+                            && !client.has_enough_funds(amount)
+                        {
+                            return Err(TransactionError::WouldGoNegative(self.client_id));
+                        }
+                        if let Some(floor) = config.min_available {
+                            if floor
+                                .checked_add(amount)
+                                .is_none_or(|needed| !client.has_enough_funds(needed))
+                            {
+                                return Err(TransactionError::WouldExceedExposure(self.client_id));
+                            }
+                        }
+                        client.hold_funds(amount)?;
                     }
-                    OperationKind::Withdrawal(amount) => client.hold_funds(amount),
+                    OperationKind::Withdrawal(_) | OperationKind::Authorization(_) => {
+                        client.hold_returned_funds(amount)?
+                    }
+                }
+                operation.held_for_dispute = held_for_dispute;
+                client.set_held_entry(self.transaction_id, held_for_dispute);
+                if !operation.disputed {
+                    operation.disputed = true;
+                    operation.dispute_count += 1;
                 }
-                operation.disputed = true;
             }
-            ClientClaimKind::Resolve if operation.disputed => {
+            (ClientClaimKind::Resolve, _) if operation.disputed => {
                 // Negative held funds is treated as an error
+                let held = operation.held_for_dispute;
                 match operation.operation_kind {
-                    OperationKind::Deposit(amount) => {
-                        client.release_funds(amount)?;
+                    OperationKind::Deposit(_) => client.release_funds(held)?,
+                    OperationKind::Withdrawal(_) | OperationKind::Authorization(_) => {
+                        client.clear_held_funds(held)?
                     }
-                    OperationKind::Withdrawal(amount) => client.clear_held_funds(amount)?,
                 }
+                operation.held_for_dispute = Amount::ZERO;
                 operation.disputed = false;
+                operation.resolved_once = true;
+                client.remove_held_entry(self.transaction_id);
+                if config.resolve_unlocks {
+                    client.unlock();
+                }
             }
-            ClientClaimKind::Chargeback if !operation.disputed => {
+            (ClientClaimKind::Chargeback, _) if operation.disputed => {
+                let held = operation.held_for_dispute;
                 match operation.operation_kind {
-                    OperationKind::Deposit(amount) => {
-                        client.clear_held_funds(amount)?;
-                    }
-                    OperationKind::Withdrawal(amount) => {
-                        client.release_funds(amount)?;
+                    OperationKind::Deposit(_) => client.clear_held_funds(held)?,
+                    OperationKind::Withdrawal(_) | OperationKind::Authorization(_) => {
+                        client.release_funds(held)?
                     }
                 }
+                operation.held_for_dispute = Amount::ZERO;
                 operation.disputed = false;
-                client.locked = true;
+                client.remove_held_entry(self.transaction_id);
+                client.lock(LockReason::Chargeback(self.transaction_id));
+                if config.reuse_charged_back_ids {
+                    operations_register.mark_terminal(self.client_id, self.transaction_id);
+                    return Ok(());
+                }
             }
+            (ClientClaimKind::Resolve, _) if noop_resolve => return Ok(()),
             _ => return Err(TransactionError::WrongTransactionState),
         }
+        operations_register.record_claim(self.client_id, self.transaction_id, self.claim_kind);
         Ok(())
     }
 }
@@ -0,0 +1,44 @@
+use std::process::Command;
+
+#[test]
+fn missing_file() {
+    let output = Command::new(env!("CARGO_BIN_EXE_payment_engine"))
+        .arg("no_such_file.csv")
+        .output()
+        .unwrap();
+    assert_eq!(Some(2), output.status.code());
+    assert!(String::from_utf8_lossy(&output.stderr).starts_with("Cannot open no_such_file.csv: "));
+}
+
+#[test]
+fn quiet() {
+    let path = std::env::temp_dir().join("payment_engine_quiet.csv");
+    std::fs::write(
+        &path,
+        "type,client,tx,amount\ndeposit,1,1,2.0\nwithdrawal,1,2,5.0\n",
+    )
+    .unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_payment_engine"))
+        .env("RUST_LOG", "debug")
+        .arg("--quiet")
+        .arg(&path)
+        .output()
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert!(output.status.success());
+    assert!(output.stderr.is_empty());
+    assert_eq!(
+        "client,available,held,total,locked\n1,2.0,0.0,2.0,false\n",
+        String::from_utf8_lossy(&output.stdout)
+    );
+}
+
+#[test]
+fn decimals_above_precision() {
+    let output = Command::new(env!("CARGO_BIN_EXE_payment_engine"))
+        .args(["--decimals", "8", "no_such_file.csv"])
+        .output()
+        .unwrap();
+    assert_eq!(Some(2), output.status.code());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--decimals"));
+}